@@ -0,0 +1,56 @@
+/*
+ * SHREK native plugin interface.
+ *
+ * A plugin is a shared library that exports two functions:
+ *
+ *   uint32_t shrek_plugin_abi_version(void);
+ *       Must return SHREK_PLUGIN_ABI_VERSION. Plugins built against a different version are rejected.
+ *
+ *   const ShrekPluginFunction *shrek_plugin_functions(size_t *count);
+ *       Returns a table of functions to register and writes the number of entries to count. The table must stay
+ *       valid for as long as the library is loaded.
+ *
+ * Each function is registered on the VM with its func_num, which must be above SHREK_BUILTIN_MAX. Before a
+ * function is called the VM checks that at least `arity` items are on the stack. The function interacts with the
+ * stack through the callbacks in ShrekApi and returns 0 on success. Any other value stops the program with a
+ * runtime error.
+ */
+#ifndef SHREK_PLUGIN_H
+#define SHREK_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define SHREK_PLUGIN_ABI_VERSION 1
+#define SHREK_BUILTIN_MAX 63
+
+typedef struct ShrekApi {
+    /* Opaque handle to the VM. Pass this as the first argument of the callbacks below. */
+    void *vm;
+
+    /* Push a value to the top of the stack. */
+    void (*push)(void *vm, int32_t value);
+
+    /* Pop the top of the stack into *value. Returns 0 on success, or non-zero if the stack is empty or the value
+     * does not fit in 32 bits. A value that does not fit is left on the stack. */
+    int32_t (*pop)(void *vm, int32_t *value);
+
+    /* Copy the top of the stack into *value. Returns 0 on success, or non-zero if the stack is empty or the value
+     * does not fit in 32 bits. */
+    int32_t (*peek)(void *vm, int32_t *value);
+
+    /* Number of items on the stack. */
+    size_t (*count)(void *vm);
+} ShrekApi;
+
+typedef int32_t (*ShrekNativeFunction)(const ShrekApi *api);
+
+typedef struct ShrekPluginFunction {
+    /* Null terminated name used in error messages. */
+    const char *name;
+    int32_t func_num;
+    uint32_t arity;
+    ShrekNativeFunction func;
+} ShrekPluginFunction;
+
+#endif /* SHREK_PLUGIN_H */
@@ -0,0 +1,87 @@
+//! Example native SHREK plugin. This mirrors `include/shrek_plugin.h` without depending on the interpreter crate,
+//! the same way a plugin written in C would.
+
+use std::os::raw::c_char;
+
+const SHREK_PLUGIN_ABI_VERSION: u32 = 1;
+
+#[repr(C)]
+pub struct ShrekApi {
+    vm: *mut std::ffi::c_void,
+    push: extern "C" fn(vm: *mut std::ffi::c_void, value: i32),
+    pop: extern "C" fn(vm: *mut std::ffi::c_void, value: *mut i32) -> i32,
+    peek: extern "C" fn(vm: *mut std::ffi::c_void, value: *mut i32) -> i32,
+    count: extern "C" fn(vm: *mut std::ffi::c_void) -> usize,
+}
+
+#[repr(C)]
+pub struct ShrekPluginFunction {
+    name: *const c_char,
+    func_num: i32,
+    arity: u32,
+    func: unsafe extern "C" fn(api: *const ShrekApi) -> i32,
+}
+
+// The table only holds pointers to static data, so it is safe to share.
+struct FunctionTable([ShrekPluginFunction; 3]);
+unsafe impl Sync for FunctionTable {}
+
+static FUNCTIONS: FunctionTable = FunctionTable([
+    ShrekPluginFunction {
+        name: b"max\0".as_ptr() as *const c_char,
+        func_num: 64,
+        arity: 2,
+        func: max,
+    },
+    ShrekPluginFunction {
+        name: b"depth\0".as_ptr() as *const c_char,
+        func_num: 65,
+        arity: 0,
+        func: depth,
+    },
+    ShrekPluginFunction {
+        name: b"fail\0".as_ptr() as *const c_char,
+        func_num: 66,
+        arity: 0,
+        func: fail,
+    },
+]);
+
+#[no_mangle]
+pub extern "C" fn shrek_plugin_abi_version() -> u32 {
+    SHREK_PLUGIN_ABI_VERSION
+}
+
+/// # Safety
+///
+/// `count` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn shrek_plugin_functions(count: *mut usize) -> *const ShrekPluginFunction {
+    *count = FUNCTIONS.0.len();
+    FUNCTIONS.0.as_ptr()
+}
+
+/// Pop two values and push the larger one.
+unsafe extern "C" fn max(api: *const ShrekApi) -> i32 {
+    let api = &*api;
+    let mut v0 = 0;
+    let mut v1 = 0;
+    if (api.pop)(api.vm, &mut v0) != 0 || (api.pop)(api.vm, &mut v1) != 0 {
+        return 1;
+    }
+    (api.push)(api.vm, v0.max(v1));
+    0
+}
+
+/// Push the number of items on the stack.
+unsafe extern "C" fn depth(api: *const ShrekApi) -> i32 {
+    let api = &*api;
+    let count = (api.count)(api.vm);
+    (api.push)(api.vm, count as i32);
+    0
+}
+
+/// Always fails.
+unsafe extern "C" fn fail(_api: *const ShrekApi) -> i32 {
+    42
+}
@@ -0,0 +1,183 @@
+//! Integer arithmetic shared by the builtins and the optimizer, so constants folded at compile time always match what
+//! the VM would compute at run time.
+
+use crate::cell::CellValue;
+use std::fmt;
+use std::str::FromStr;
+
+/// How arithmetic results that do not fit in a stack value are handled. The behavior is the same in debug and release
+/// builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArithmeticMode {
+    /// Overflow is a runtime error.
+    #[default]
+    Checked,
+    /// Results wrap around at the boundary of the type.
+    Wrapping,
+    /// Results are clamped to the minimum or maximum value.
+    Saturating,
+}
+
+/// Why an arithmetic operation has no result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticError {
+    Overflow,
+    DivisionByZero,
+}
+
+pub type ArithmeticResult<T> = Result<T, ArithmeticError>;
+
+impl ArithmeticMode {
+    pub fn add<T: CellValue>(self, v1: T, v0: T) -> ArithmeticResult<T> {
+        match self {
+            ArithmeticMode::Checked => v1.checked_add(v0).ok_or(ArithmeticError::Overflow),
+            ArithmeticMode::Wrapping => Ok(v1.wrapping_add(v0)),
+            ArithmeticMode::Saturating => Ok(v1.saturating_add(v0)),
+        }
+    }
+
+    pub fn subtract<T: CellValue>(self, v1: T, v0: T) -> ArithmeticResult<T> {
+        match self {
+            ArithmeticMode::Checked => v1.checked_sub(v0).ok_or(ArithmeticError::Overflow),
+            ArithmeticMode::Wrapping => Ok(v1.wrapping_sub(v0)),
+            ArithmeticMode::Saturating => Ok(v1.saturating_sub(v0)),
+        }
+    }
+
+    pub fn multiply<T: CellValue>(self, v1: T, v0: T) -> ArithmeticResult<T> {
+        match self {
+            ArithmeticMode::Checked => v1.checked_mul(v0).ok_or(ArithmeticError::Overflow),
+            ArithmeticMode::Wrapping => Ok(v1.wrapping_mul(v0)),
+            ArithmeticMode::Saturating => Ok(v1.saturating_mul(v0)),
+        }
+    }
+
+    /// Integer division. Dividing by zero is an error in every mode.
+    pub fn divide<T: CellValue>(self, v1: T, v0: T) -> ArithmeticResult<T> {
+        if v0.is_zero() {
+            return Err(ArithmeticError::DivisionByZero);
+        }
+
+        match self {
+            ArithmeticMode::Checked => v1.checked_div(v0).ok_or(ArithmeticError::Overflow),
+            ArithmeticMode::Wrapping => Ok(v1.wrapping_div(v0)),
+            ArithmeticMode::Saturating => Ok(v1.saturating_div(v0)),
+        }
+    }
+
+    /// Remainder of integer division. Dividing by zero is an error in every mode.
+    pub fn modulo<T: CellValue>(self, v1: T, v0: T) -> ArithmeticResult<T> {
+        if v0.is_zero() {
+            return Err(ArithmeticError::DivisionByZero);
+        }
+
+        match self {
+            ArithmeticMode::Checked => v1.checked_rem(v0).ok_or(ArithmeticError::Overflow),
+            // The only overflowing case is MIN % -1, where the remainder is 0.
+            ArithmeticMode::Wrapping | ArithmeticMode::Saturating => Ok(v1.wrapping_rem(v0)),
+        }
+    }
+
+    /// Absolute value. Like `negate`, this overflows for the minimum value of a fixed width type.
+    pub fn abs<T: CellValue>(self, v0: T) -> ArithmeticResult<T> {
+        if v0.is_negative() {
+            self.negate(v0)
+        } else {
+            Ok(v0)
+        }
+    }
+
+    pub fn negate<T: CellValue>(self, v0: T) -> ArithmeticResult<T> {
+        match self {
+            ArithmeticMode::Checked => v0.checked_neg().ok_or(ArithmeticError::Overflow),
+            ArithmeticMode::Wrapping => Ok(v0.wrapping_neg()),
+            ArithmeticMode::Saturating => Ok(v0.saturating_neg()),
+        }
+    }
+}
+
+impl FromStr for ArithmeticMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "checked" => Ok(ArithmeticMode::Checked),
+            "wrapping" => Ok(ArithmeticMode::Wrapping),
+            "saturating" => Ok(ArithmeticMode::Saturating),
+            _ => Err(format!("unknown arithmetic mode {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ArithmeticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArithmeticError::Overflow => write!(f, "integer overflow"),
+            ArithmeticError::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overflow() {
+        assert_eq!(
+            Err(ArithmeticError::Overflow),
+            ArithmeticMode::Checked.add(i32::MAX, 1)
+        );
+        assert_eq!(Ok(i32::MIN), ArithmeticMode::Wrapping.add(i32::MAX, 1));
+        assert_eq!(Ok(i32::MAX), ArithmeticMode::Saturating.add(i32::MAX, 1));
+
+        assert_eq!(
+            Err(ArithmeticError::Overflow),
+            ArithmeticMode::Checked.negate(i32::MIN)
+        );
+        assert_eq!(Ok(i32::MIN), ArithmeticMode::Wrapping.negate(i32::MIN));
+        assert_eq!(Ok(i32::MAX), ArithmeticMode::Saturating.negate(i32::MIN));
+
+        assert_eq!(
+            Err(ArithmeticError::Overflow),
+            ArithmeticMode::Checked.abs(i32::MIN)
+        );
+        assert_eq!(Ok(i32::MIN), ArithmeticMode::Wrapping.abs(i32::MIN));
+        assert_eq!(Ok(i32::MAX), ArithmeticMode::Saturating.abs(i32::MIN));
+        assert_eq!(Ok(5), ArithmeticMode::Checked.abs(-5));
+    }
+
+    #[test]
+    fn test_divide_overflow() {
+        assert_eq!(
+            Err(ArithmeticError::Overflow),
+            ArithmeticMode::Checked.divide(i32::MIN, -1)
+        );
+        assert_eq!(Ok(i32::MIN), ArithmeticMode::Wrapping.divide(i32::MIN, -1));
+        assert_eq!(
+            Ok(i32::MAX),
+            ArithmeticMode::Saturating.divide(i32::MIN, -1)
+        );
+        assert_eq!(Ok(0), ArithmeticMode::Saturating.modulo(i32::MIN, -1));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        for mode in [
+            ArithmeticMode::Checked,
+            ArithmeticMode::Wrapping,
+            ArithmeticMode::Saturating,
+        ]
+        .iter()
+        {
+            assert_eq!(Err(ArithmeticError::DivisionByZero), mode.divide(1, 0));
+            assert_eq!(Err(ArithmeticError::DivisionByZero), mode.modulo(1, 0));
+        }
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Ok(ArithmeticMode::Wrapping), "wrapping".parse());
+        assert!("unknown".parse::<ArithmeticMode>().is_err());
+    }
+}
@@ -1,9 +1,20 @@
+use crate::arithmetic::ArithmeticResult;
+use crate::cell::CellValue;
+use crate::limits::{Limit, MAX_CELL_BITS, MAX_MEMORY_BYTES};
 use crate::shrek_vm::*;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
 
-use std::io::{self, Write};
+/// Function numbers up to and including this value are reserved for builtins. Host functions registered with
+/// `ShrekVM::register_function` must use a number above this.
+pub const BUILTIN_MAX: i32 = 63;
 
-// TODO: This woudl be used to reserve builtins when/if extensions are supported.
-// pub const BUILTIN_MAX: i32 = 63;
+/// Largest shift left count for a cell type with no fixed width. Each bit shifted in makes the value bigger, so larger
+/// counts could use up all memory in a single shift.
+pub const MAX_UNBOUNDED_SHIFT: u32 = 1 << 16;
+
+/// Pushed by the input builtin, instead of a string, when there is no more input.
+pub const EOF: i32 = -1;
 
 pub mod ops {
     pub const INPUT: i32 = 0;
@@ -17,12 +28,124 @@ pub mod ops {
     pub const NEGATE: i32 = 8;
     pub const SQUARE: i32 = 9;
     pub const CLONE: i32 = 10;
+    pub const OUTPUT_CHAR: i32 = 11;
+    pub const OUTPUT_BYTE: i32 = 12;
+    pub const OUTPUT_STRING: i32 = 13;
+    pub const INPUT_BYTE: i32 = 14;
+    pub const INPUT_INT: i32 = 15;
+    pub const INPUT_ALL: i32 = 16;
+    pub const SWAP: i32 = 17;
+    pub const OVER: i32 = 18;
+    pub const ROT: i32 = 19;
+    pub const PICK: i32 = 20;
+    pub const ROLL: i32 = 21;
+    pub const DEPTH: i32 = 22;
+    pub const DROP_N: i32 = 23;
+    pub const CLEAR: i32 = 24;
+    pub const EQ: i32 = 25;
+    pub const NE: i32 = 26;
+    pub const LT: i32 = 27;
+    pub const LE: i32 = 28;
+    pub const GT: i32 = 29;
+    pub const GE: i32 = 30;
+    pub const NOT: i32 = 31;
+    pub const AND: i32 = 32;
+    pub const OR: i32 = 33;
+    pub const MIN: i32 = 34;
+    pub const MAX: i32 = 35;
+    pub const ABS: i32 = 36;
+    pub const SIGN: i32 = 37;
+    pub const BIT_AND: i32 = 38;
+    pub const BIT_OR: i32 = 39;
+    pub const BIT_XOR: i32 = 40;
+    pub const BIT_NOT: i32 = 41;
+    pub const SHIFT_LEFT: i32 = 42;
+    pub const SHIFT_RIGHT: i32 = 43;
+    pub const SHIFT_RIGHT_LOGICAL: i32 = 44;
+    pub const POPCOUNT: i32 = 45;
+    pub const LEADING_ZEROS: i32 = 46;
+    pub const LOAD: i32 = 47;
+    pub const STORE: i32 = 48;
+    pub const MEMORY_SIZE: i32 = 49;
+    pub const MEMORY_GROW: i32 = 50;
+    pub const GET_GLOBAL: i32 = 51;
+    pub const SET_GLOBAL: i32 = 52;
+}
+
+/// Name of builtin function `func_num`, as used in error messages and listings. `None` if there is no builtin with that
+/// number.
+pub fn builtin_name(func_num: i32) -> Option<&'static str> {
+    const NAMES: [&str; 53] = [
+        "input",
+        "output",
+        "add",
+        "subtract",
+        "multiply",
+        "divide",
+        "mod",
+        "double",
+        "negate",
+        "square",
+        "clone",
+        "output char",
+        "output byte",
+        "output string",
+        "input byte",
+        "input int",
+        "input all",
+        "swap",
+        "over",
+        "rot",
+        "pick",
+        "roll",
+        "depth",
+        "drop",
+        "clear",
+        "eq",
+        "ne",
+        "lt",
+        "le",
+        "gt",
+        "ge",
+        "not",
+        "and",
+        "or",
+        "min",
+        "max",
+        "abs",
+        "sign",
+        "bit and",
+        "bit or",
+        "bit xor",
+        "bit not",
+        "shift left",
+        "shift right",
+        "logical shift right",
+        "popcount",
+        "leading zeros",
+        "load",
+        "store",
+        "memory size",
+        "memory grow",
+        "get global",
+        "set global",
+    ];
+
+    usize::try_from(func_num)
+        .ok()
+        .and_then(|index| NAMES.get(index))
+        .copied()
 }
 
-pub fn execute_builtin(vm: &mut ShrekVM, func_num: i32) -> VmResult<()> {
+/// Name of a builtin that is known to exist, for error messages.
+fn op_name(func_num: i32) -> &'static str {
+    builtin_name(func_num).unwrap_or("unknown builtin")
+}
+
+pub fn execute_builtin<C: CellValue>(vm: &mut ShrekVM<C>, func_num: i32) -> VmResult<()> {
     match func_num {
-        ops::INPUT => input(vm, read_line_stdio, prompt_stdout),
-        ops::OUTPUT => output(vm, write_stdout),
+        ops::INPUT => input(vm),
+        ops::OUTPUT => output(vm),
         ops::ADD => add(vm),
         ops::SUBTRACT => subtract(vm),
         ops::MULTIPLY => multiply(vm),
@@ -32,196 +155,566 @@ pub fn execute_builtin(vm: &mut ShrekVM, func_num: i32) -> VmResult<()> {
         ops::NEGATE => negate(vm),
         ops::SQUARE => square(vm),
         ops::CLONE => clone(vm),
-        _ => Err(ShrekRuntimeError::new("invalid builtin function number")),
+        ops::OUTPUT_CHAR => output_char(vm),
+        ops::OUTPUT_BYTE => output_byte(vm),
+        ops::OUTPUT_STRING => output_string(vm),
+        ops::INPUT_BYTE => input_byte(vm),
+        ops::INPUT_INT => input_int(vm),
+        ops::INPUT_ALL => input_all(vm),
+        ops::SWAP => swap(vm),
+        ops::OVER => over(vm),
+        ops::ROT => rot(vm),
+        ops::PICK => pick(vm),
+        ops::ROLL => roll(vm),
+        ops::DEPTH => depth(vm),
+        ops::DROP_N => drop_n(vm),
+        ops::CLEAR => clear(vm),
+        ops::EQ
+        | ops::NE
+        | ops::LT
+        | ops::LE
+        | ops::GT
+        | ops::GE
+        | ops::AND
+        | ops::OR
+        | ops::MIN
+        | ops::MAX => logic_2_arg(vm, func_num),
+        ops::NOT | ops::SIGN => logic_1_arg(vm, func_num),
+        ops::ABS => abs(vm),
+        ops::BIT_AND
+        | ops::BIT_OR
+        | ops::BIT_XOR
+        | ops::SHIFT_LEFT
+        | ops::SHIFT_RIGHT
+        | ops::SHIFT_RIGHT_LOGICAL => bitwise_2_arg(vm, func_num),
+        ops::BIT_NOT | ops::POPCOUNT | ops::LEADING_ZEROS => bitwise_1_arg(vm, func_num),
+        ops::LOAD => load(vm),
+        ops::STORE => store(vm),
+        ops::MEMORY_SIZE => memory_size(vm),
+        ops::MEMORY_GROW => memory_grow(vm),
+        ops::GET_GLOBAL => get_global(vm),
+        ops::SET_GLOBAL => set_global(vm),
+        _ => Err(ErrorKind::UnknownFunction(func_num).into()),
+    }
+}
+
+/// Apply a comparison or logical builtin that takes two items to `{2}` (v1) and `{1}` (v0). Comparisons and logical
+/// operations push 1 for true and 0 for false. Returns `None` if `func_num` is not one of these builtins. The
+/// optimizer uses this to fold constants, so it always matches the VM.
+pub(crate) fn apply_logic_2_arg<C: CellValue>(func_num: i32, v1: C, v0: C) -> Option<C> {
+    let result = match func_num {
+        ops::EQ => v1 == v0,
+        ops::NE => v1 != v0,
+        ops::LT => v1 < v0,
+        ops::LE => v1 <= v0,
+        ops::GT => v1 > v0,
+        ops::GE => v1 >= v0,
+        ops::AND => !v1.is_zero() && !v0.is_zero(),
+        ops::OR => !v1.is_zero() || !v0.is_zero(),
+        ops::MIN => return Some(v1.min(v0)),
+        ops::MAX => return Some(v1.max(v0)),
+        _ => return None,
+    };
+    Some(C::from_bool(result))
+}
+
+/// Apply a logical builtin that takes one item. Returns `None` if `func_num` is not one of these builtins.
+pub(crate) fn apply_logic_1_arg<C: CellValue>(func_num: i32, v0: C) -> Option<C> {
+    match func_num {
+        ops::NOT => Some(C::from_bool(v0.is_zero())),
+        ops::SIGN => Some(match v0.cmp(&C::default()) {
+            Ordering::Less => C::from_i32(-1),
+            Ordering::Equal => C::default(),
+            Ordering::Greater => C::from_i32(1),
+        }),
+        _ => None,
     }
 }
 
-/// Simple function that will be used to dependency inject getting a string from stdin.
-fn read_line_stdio() -> Option<String> {
+/// Apply a bitwise builtin that takes two items to `{2}` (v1) and `{1}` (v0). For shifts, v0 is the number of bits
+/// to shift by. Returns `None` if `func_num` is not one of these builtins, or an error message for arguments the
+/// builtin does not accept. Shared with the optimizer like `apply_logic_2_arg`.
+pub(crate) fn apply_bitwise_2_arg<C: CellValue>(
+    func_num: i32,
+    v1: C,
+    v0: C,
+) -> Option<Result<C, String>> {
+    let shift = || -> Result<u32, String> {
+        if v0.is_negative() {
+            return Err(format!("shift count must be 0 or more, got {}", v0));
+        }
+        // Counts too large for a u32 shift every bit out of a fixed width type anyway.
+        Ok(v0.to_i32_saturating() as u32)
+    };
+
+    let result = match func_num {
+        ops::BIT_AND => Ok(v1.bit_and(v0.clone())),
+        ops::BIT_OR => Ok(v1.bit_or(v0.clone())),
+        ops::BIT_XOR => Ok(v1.bit_xor(v0.clone())),
+        ops::SHIFT_LEFT => match shift() {
+            Ok(n) if C::bits().is_none() && n > MAX_UNBOUNDED_SHIFT => Err(format!(
+                "shift count must be at most {} without a fixed width, got {}",
+                MAX_UNBOUNDED_SHIFT, v0
+            )),
+            result => result.map(|n| v1.shl(n)),
+        },
+        ops::SHIFT_RIGHT => shift().map(|n| v1.shr(n)),
+        ops::SHIFT_RIGHT_LOGICAL => {
+            let n = match shift() {
+                Ok(n) => n,
+                Err(err) => return Some(Err(err)),
+            };
+            v1.clone()
+                .shr_logical(n)
+                .ok_or_else(|| format!("cannot shift negative value {} without a fixed width", v1))
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// Apply a bitwise builtin that takes one item. Returns `None` if `func_num` is not one of these builtins, or an
+/// error message for arguments the builtin does not accept.
+pub(crate) fn apply_bitwise_1_arg<C: CellValue>(func_num: i32, v0: C) -> Option<Result<C, String>> {
+    let count = match func_num {
+        ops::BIT_NOT => return Some(Ok(v0.bit_not())),
+        ops::POPCOUNT => v0.count_ones(),
+        ops::LEADING_ZEROS => v0.leading_zeros(),
+        _ => return None,
+    };
+
+    Some(match count {
+        Some(x) => Ok(C::from_i32(x as i32)),
+        None => Err(format!("{} has no fixed number of bits", v0)),
+    })
+}
+
+/// Read a line from the VM's input stream.
+fn read_line<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<String> {
     let mut buffer = String::new();
-    match io::stdin().read_line(&mut buffer) {
-        Err(_) => None,
-        Ok(_) => Some(buffer),
+    match vm.input().read_line(&mut buffer) {
+        Err(_) => Err(ShrekRuntimeError::with_kind(
+            ErrorKind::Io,
+            "Error reading input",
+        )),
+        Ok(_) => Ok(buffer),
     }
 }
 
-/// Promper for user input. Used for dependency injection.
-fn prompt_stdout() -> VmResult<()> {
+/// Read a single byte from the VM's input stream. Returns `None` at the end of the input.
+fn read_byte<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<Option<u8>> {
+    let input = vm.input();
+    let byte = match input.fill_buf() {
+        Err(_) => {
+            return Err(ShrekRuntimeError::with_kind(
+                ErrorKind::Io,
+                "Error reading input",
+            ))
+        }
+        Ok(buffer) => buffer.first().copied(),
+    };
+
+    if byte.is_some() {
+        input.consume(1);
+    }
+    Ok(byte)
+}
+
+/// Read everything left in the VM's input stream.
+fn read_all<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<Vec<u8>> {
+    let mut buffer = Vec::new();
+    match vm.input().read_to_end(&mut buffer) {
+        Err(_) => Err(ShrekRuntimeError::with_kind(
+            ErrorKind::Io,
+            "Error reading input",
+        )),
+        Ok(_) => Ok(buffer),
+    }
+}
+
+/// Push a null terminated string in reverse order, so popping the stack returns it in the forward direction.
+fn push_string<C: CellValue>(vm: &mut ShrekVM<C>, bytes: &[u8]) {
+    vm.push(C::default());
+    for c in bytes.iter().rev() {
+        vm.push(C::from_i32(*c as i32));
+    }
+}
+
+/// Prompt for user input on the VM's output stream.
+fn prompt<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     // Must flush this immediately, otherwise output will be buffered and not show this prompt.
-    print!("input: ");
-    match io::stdout().flush() {
-        Err(_) => return Err(ShrekRuntimeError::new("i/o error writing to stdout")),
+    let out = vm.output();
+    match write!(out, "input: ").and_then(|_| out.flush()) {
+        Err(_) => Err(ShrekRuntimeError::with_kind(
+            ErrorKind::Io,
+            "i/o error writing output",
+        )),
         _ => Ok(()),
     }
 }
 
-// Write a value to stdout. Used for dependency injection.
-fn write_stdout(val: i32) {
-    println!("{}", val);
+/// Write a value to the VM's output stream.
+fn write_value<C: CellValue>(vm: &mut ShrekVM<C>, val: C) -> VmResult<()> {
+    match writeln!(vm.output(), "{}", val) {
+        Err(_) => Err(ShrekRuntimeError::with_kind(
+            ErrorKind::Io,
+            "i/o error writing output",
+        )),
+        _ => Ok(()),
+    }
 }
 
-fn input<I, P>(vm: &mut ShrekVM, read_line: I, prompt: P) -> VmResult<()>
-where
-    I: FnOnce() -> Option<String>,
-    P: FnOnce() -> VmResult<()>,
-{
-    // Prompt for input (injected function). This can fail.
-    prompt()?;
+/// Write raw bytes to the VM's output stream.
+fn write_bytes<C: CellValue>(vm: &mut ShrekVM<C>, bytes: &[u8]) -> VmResult<()> {
+    match vm.output().write_all(bytes) {
+        Err(_) => Err(ShrekRuntimeError::with_kind(
+            ErrorKind::Io,
+            "i/o error writing output",
+        )),
+        _ => Ok(()),
+    }
+}
 
-    // Read line (injected function). This can fail.
-    let mut buffer = match read_line() {
-        Some(x) => x,
-        None => return Err(ShrekRuntimeError::new("Error reading input")),
-    };
+/// The low 8 bits of a value, the same way C's `putchar` converts its argument.
+fn to_byte<C: CellValue>(val: C) -> u8 {
+    let mut byte = val.wrapping_rem(C::from_i32(256));
+    if byte.is_negative() {
+        byte = byte.wrapping_add(C::from_i32(256));
+    }
+    byte.to_i32().unwrap_or_default() as u8
+}
+
+/// The error for a builtin called `name` that needs `needed` items on the stack.
+fn stack_underflow<C: CellValue>(vm: &ShrekVM<C>, name: &str, needed: usize) -> ShrekRuntimeError {
+    let items = if needed == 1 { "item" } else { "items" };
+    ShrekRuntimeError::with_kind(
+        ErrorKind::StackUnderflow {
+            needed,
+            had: vm.count(),
+        },
+        &format!("{} requires {} {} on the stack", name, needed, items),
+    )
+}
+
+/// Convert a count given to the builtin called `name` to a `usize`. Negative counts are an error.
+fn count_value<C: CellValue>(name: &str, value: &C) -> VmResult<usize> {
+    match value.to_i32().and_then(|x| usize::try_from(x).ok()) {
+        Some(x) => Ok(x),
+        None => Err(invalid_argument(
+            name,
+            &format!("count must be from 0 to {}, got {}", i32::MAX, value),
+        )),
+    }
+}
+
+/// Check that `{0}` is a count for the builtin called `name` and that `extra` more items are on the stack below
+/// the count. The count is not popped, so the stack is unchanged if the check fails.
+fn peek_count<C: CellValue>(vm: &ShrekVM<C>, name: &str, extra: usize) -> VmResult<usize> {
+    if vm.count() < 1 {
+        return Err(stack_underflow(vm, name, 1));
+    }
+
+    let count = count_value(name, &vm.peek()?)?;
+
+    let needed = count.saturating_add(extra).saturating_add(1);
+    if vm.count() < needed {
+        return Err(stack_underflow(vm, name, needed));
+    }
+    Ok(count)
+}
+
+fn invalid_argument(name: &str, message: &str) -> ShrekRuntimeError {
+    ShrekRuntimeError::with_kind(
+        ErrorKind::InvalidArgument,
+        &format!("{}: {}", name, message),
+    )
+}
+
+/// Check that `address` is inside the VM's memory.
+fn memory_index<C: CellValue>(vm: &ShrekVM<C>, address: &C) -> VmResult<usize> {
+    let size = vm.memory().len();
+    match address.to_i32().and_then(|x| usize::try_from(x).ok()) {
+        Some(index) if index < size => Ok(index),
+        _ => Err(ErrorKind::MemoryOutOfBounds {
+            address: address.to_i32_saturating(),
+            size,
+        }
+        .into()),
+    }
+}
+
+/// Check that `slot` is a global variable slot for the builtin called `name`.
+fn global_index<C: CellValue>(name: &str, slot: &C) -> VmResult<usize> {
+    match slot.to_i32().and_then(|x| usize::try_from(x).ok()) {
+        Some(index) if index < GLOBAL_SLOTS => Ok(index),
+        _ => Err(invalid_argument(
+            name,
+            &format!("slot must be from 0 to {}, got {}", GLOBAL_SLOTS - 1, slot),
+        )),
+    }
+}
+
+/// Convert a size to a stack value.
+fn size_value<C: CellValue>(size: usize) -> C {
+    C::from_i32(i32::try_from(size).unwrap_or(i32::MAX))
+}
+
+/// Turn the result of an arithmetic builtin into a runtime error naming the builtin if it failed.
+fn checked_result<C: CellValue>(name: &str, result: ArithmeticResult<C>) -> VmResult<C> {
+    let val = result.map_err(|err| ShrekRuntimeError::arithmetic(err, name))?;
+    check_cell_size(val)
+}
+
+/// Stop a value with no fixed width from growing past `MAX_CELL_BITS`.
+fn check_cell_size<C: CellValue>(val: C) -> VmResult<C> {
+    if val.bit_len() > MAX_CELL_BITS {
+        return Err(ErrorKind::LimitExceeded(Limit::CellSize).into());
+    }
+
+    Ok(val)
+}
+
+fn input<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    // Prompt for input. This can fail.
+    if vm.prompt() {
+        prompt(vm)?;
+    }
+
+    // Read line. This can fail.
+    let mut buffer = read_line(vm)?;
+
+    // Nothing read, not even a newline, means the end of the input.
+    if buffer.is_empty() {
+        vm.push(C::from_i32(EOF));
+        return Ok(());
+    }
 
     // Readline includes newline char, so trim it out.
     buffer = buffer.trim_end().to_string();
 
-    // Add a null terminator.
-    vm.push(0);
+    // These will be added as raw bytes.
+    push_string(vm, buffer.as_bytes());
+
+    Ok(())
+}
+
+/// Push the next byte of input, or `EOF` at the end of the input. There is no prompt, so this can be used to read
+/// binary data.
+fn input_byte<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    let val = match read_byte(vm)? {
+        Some(byte) => C::from_i32(byte as i32),
+        None => C::from_i32(EOF),
+    };
+    vm.push(val);
+
+    Ok(())
+}
 
-    // Add the string in reverse order, so popping off the stack will be in the forward
-    // direction. These will be added as raw bytes.
-    for c in buffer.bytes().rev() {
-        vm.push(c as i32);
+/// Read a line holding a decimal integer. Pushes the integer and 1, or only 0 at the end of the input. A line that
+/// is not an integer, or does not fit in a stack value, is an error.
+fn input_int<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    if vm.prompt() {
+        prompt(vm)?;
     }
 
+    let buffer = read_line(vm)?;
+    if buffer.is_empty() {
+        vm.push(C::default());
+        return Ok(());
+    }
+
+    match buffer.trim().parse::<C>() {
+        Ok(val) => {
+            vm.push(val);
+            vm.push(C::from_i32(1));
+            Ok(())
+        }
+        Err(_) => Err(ShrekRuntimeError::with_kind(
+            ErrorKind::InvalidInput,
+            &format!("{:?} is not an integer", buffer.trim_end()),
+        )),
+    }
+}
+
+/// Read the rest of the input, without a prompt, and push it as a single null terminated string. Newlines are kept.
+fn input_all<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    let buffer = read_all(vm)?;
+    push_string(vm, &buffer);
+
     Ok(())
 }
 
-fn output<O>(vm: &mut ShrekVM, ouput_func: O) -> VmResult<()>
-where
-    O: FnOnce(i32) -> (),
-{
+fn output<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     let v0 = vm.peek()?;
-    ouput_func(v0);
-    Ok(())
+    write_value(vm, v0)
+}
+
+/// Write `{0}` as a Unicode character, without popping it. Values that are not a Unicode scalar value are written as
+/// U+FFFD REPLACEMENT CHARACTER.
+fn output_char<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    let v0 = vm.peek()?;
+    let c = v0
+        .to_i32()
+        .and_then(|x| std::char::from_u32(x as u32))
+        .unwrap_or(std::char::REPLACEMENT_CHARACTER);
+
+    let mut buffer = [0; 4];
+    write_bytes(vm, c.encode_utf8(&mut buffer).as_bytes())
 }
 
-fn add(vm: &mut ShrekVM) -> VmResult<()> {
+/// Write the low 8 bits of `{0}` as a single byte, without popping it.
+fn output_byte<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    let v0 = vm.peek()?;
+    write_bytes(vm, &[to_byte(v0)])
+}
+
+/// Pop a null terminated string, like the ones pushed by input, and write it as bytes. The stack is left unchanged
+/// if it has no null terminator.
+fn output_string<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    let length = match vm.stack().iter().rev().position(|x| x.is_zero()) {
+        Some(x) => x,
+        None => {
+            return Err(stack_underflow(
+                vm,
+                op_name(ops::OUTPUT_STRING),
+                vm.count() + 1,
+            ))
+        }
+    };
+
+    let mut bytes = Vec::with_capacity(length);
+    for _ in 0..length {
+        bytes.push(to_byte(vm.pop()?));
+    }
+    vm.pop()?;
+
+    write_bytes(vm, &bytes)
+}
+
+fn add<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     if vm.count() < 2 {
-        Err(ShrekRuntimeError::new("add requires 2 items on the stack"))
+        Err(stack_underflow(vm, op_name(ops::ADD), 2))
     } else {
         let v0 = vm.pop()?;
         let v1 = vm.pop()?;
 
-        let val = v1 + v0;
+        let val = checked_result(op_name(ops::ADD), vm.arithmetic_mode().add(v1, v0))?;
         vm.push(val);
 
         Ok(())
     }
 }
 
-fn subtract(vm: &mut ShrekVM) -> VmResult<()> {
+fn subtract<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     if vm.count() < 2 {
-        Err(ShrekRuntimeError::new(
-            "subtract requires 2 items on the stack",
-        ))
+        Err(stack_underflow(vm, op_name(ops::SUBTRACT), 2))
     } else {
         let v0 = vm.pop()?;
         let v1 = vm.pop()?;
 
-        let val = v1 - v0;
+        let val = checked_result(
+            op_name(ops::SUBTRACT),
+            vm.arithmetic_mode().subtract(v1, v0),
+        )?;
         vm.push(val);
 
         Ok(())
     }
 }
 
-fn multiply(vm: &mut ShrekVM) -> VmResult<()> {
+fn multiply<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     if vm.count() < 2 {
-        Err(ShrekRuntimeError::new(
-            "multiply requires 2 items on the stack",
-        ))
+        Err(stack_underflow(vm, op_name(ops::MULTIPLY), 2))
     } else {
         let v0 = vm.pop()?;
         let v1 = vm.pop()?;
 
-        let val = v1 * v0;
+        let val = checked_result(
+            op_name(ops::MULTIPLY),
+            vm.arithmetic_mode().multiply(v1, v0),
+        )?;
         vm.push(val);
 
         Ok(())
     }
 }
 
-fn divide(vm: &mut ShrekVM) -> VmResult<()> {
+fn divide<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     if vm.count() < 2 {
-        Err(ShrekRuntimeError::new(
-            "divide requires 2 items on the stack",
-        ))
+        Err(stack_underflow(vm, op_name(ops::DIVIDE), 2))
     } else {
         let v0 = vm.pop()?;
         let v1 = vm.pop()?;
 
-        let val = v1 / v0;
+        let val = checked_result(op_name(ops::DIVIDE), vm.arithmetic_mode().divide(v1, v0))?;
         vm.push(val);
 
         Ok(())
     }
 }
 
-fn mod_(vm: &mut ShrekVM) -> VmResult<()> {
+fn mod_<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     if vm.count() < 2 {
-        Err(ShrekRuntimeError::new("mod requires 2 items on the stack"))
+        Err(stack_underflow(vm, op_name(ops::MOD_), 2))
     } else {
         let v0 = vm.pop()?;
         let v1 = vm.pop()?;
 
-        let val = v1 % v0;
+        let val = checked_result(op_name(ops::MOD_), vm.arithmetic_mode().modulo(v1, v0))?;
         vm.push(val);
 
         Ok(())
     }
 }
 
-fn double_val(vm: &mut ShrekVM) -> VmResult<()> {
+fn double_val<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     if vm.count() < 1 {
-        Err(ShrekRuntimeError::new(
-            "double_val requires 1 item on the stack",
-        ))
+        Err(stack_underflow(vm, op_name(ops::DOUBLE_VAL), 1))
     } else {
         let v0 = vm.pop()?;
 
-        let val = v0 * 2;
+        let val = checked_result(
+            op_name(ops::DOUBLE_VAL),
+            vm.arithmetic_mode().multiply(v0, C::from_i32(2)),
+        )?;
         vm.push(val);
 
         Ok(())
     }
 }
 
-fn negate(vm: &mut ShrekVM) -> VmResult<()> {
+fn negate<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     if vm.count() < 1 {
-        Err(ShrekRuntimeError::new(
-            "negate requires 1 item on the stack",
-        ))
+        Err(stack_underflow(vm, op_name(ops::NEGATE), 1))
     } else {
         let v0 = vm.pop()?;
 
-        let val = -v0;
+        let val = checked_result(op_name(ops::NEGATE), vm.arithmetic_mode().negate(v0))?;
         vm.push(val);
 
         Ok(())
     }
 }
 
-fn square(vm: &mut ShrekVM) -> VmResult<()> {
+fn square<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     if vm.count() < 1 {
-        Err(ShrekRuntimeError::new(
-            "square requires 1 item on the stack",
-        ))
+        Err(stack_underflow(vm, op_name(ops::SQUARE), 1))
     } else {
         let v0 = vm.pop()?;
 
-        let val = v0 * v0;
+        let val = checked_result(
+            op_name(ops::SQUARE),
+            vm.arithmetic_mode().multiply(v0.clone(), v0),
+        )?;
         vm.push(val);
 
         Ok(())
     }
 }
 
-fn clone(vm: &mut ShrekVM) -> VmResult<()> {
+fn clone<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
     if vm.count() < 1 {
-        Err(ShrekRuntimeError::new("clone requires 1 item on the stack"))
+        Err(stack_underflow(vm, op_name(ops::CLONE), 1))
     } else {
         let v0 = vm.peek()?;
         vm.push(v0);
@@ -230,19 +723,273 @@ fn clone(vm: &mut ShrekVM) -> VmResult<()> {
     }
 }
 
+fn swap<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    if vm.count() < 2 {
+        Err(stack_underflow(vm, op_name(ops::SWAP), 2))
+    } else {
+        let len = vm.count();
+        vm.stack_mut().swap(len - 1, len - 2);
+
+        Ok(())
+    }
+}
+
+fn over<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    if vm.count() < 2 {
+        Err(stack_underflow(vm, op_name(ops::OVER), 2))
+    } else {
+        let v1 = vm.stack()[vm.count() - 2].clone();
+        vm.push(v1);
+
+        Ok(())
+    }
+}
+
+/// Move `{2}` to the top of the stack.
+fn rot<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    if vm.count() < 3 {
+        Err(stack_underflow(vm, op_name(ops::ROT), 3))
+    } else {
+        let len = vm.count();
+        vm.stack_mut()[len - 3..].rotate_left(1);
+
+        Ok(())
+    }
+}
+
+/// Pop n and copy the nth item, counting from 0 at the new top of the stack, to the top.
+fn pick<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    let n = peek_count(vm, op_name(ops::PICK), 1)?;
+    vm.pop()?;
+
+    let val = vm.stack()[vm.count() - 1 - n].clone();
+    vm.push(val);
+
+    Ok(())
+}
+
+/// Pop n and move the nth item, counting from 0 at the new top of the stack, to the top.
+fn roll<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    let n = peek_count(vm, op_name(ops::ROLL), 1)?;
+    vm.pop()?;
+
+    let len = vm.count();
+    vm.stack_mut()[len - 1 - n..].rotate_left(1);
+
+    Ok(())
+}
+
+/// Push the number of items on the stack.
+fn depth<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    let count = size_value(vm.count());
+    vm.push(count);
+
+    Ok(())
+}
+
+/// Pop n, then pop n more items.
+fn drop_n<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    let n = peek_count(vm, op_name(ops::DROP_N), 0)?;
+    vm.pop()?;
+
+    let len = vm.count();
+    vm.stack_mut().truncate(len - n);
+
+    Ok(())
+}
+
+fn clear<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    vm.stack_mut().clear();
+
+    Ok(())
+}
+
+fn logic_2_arg<C: CellValue>(vm: &mut ShrekVM<C>, func_num: i32) -> VmResult<()> {
+    let name = op_name(func_num);
+    if vm.count() < 2 {
+        Err(stack_underflow(vm, name, 2))
+    } else {
+        let v0 = vm.pop()?;
+        let v1 = vm.pop()?;
+
+        if let Some(val) = apply_logic_2_arg(func_num, v1, v0) {
+            vm.push(val);
+        }
+
+        Ok(())
+    }
+}
+
+fn logic_1_arg<C: CellValue>(vm: &mut ShrekVM<C>, func_num: i32) -> VmResult<()> {
+    let name = op_name(func_num);
+    if vm.count() < 1 {
+        Err(stack_underflow(vm, name, 1))
+    } else {
+        let v0 = vm.pop()?;
+
+        if let Some(val) = apply_logic_1_arg(func_num, v0) {
+            vm.push(val);
+        }
+
+        Ok(())
+    }
+}
+
+fn abs<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    if vm.count() < 1 {
+        Err(stack_underflow(vm, op_name(ops::ABS), 1))
+    } else {
+        let v0 = vm.pop()?;
+
+        let val = checked_result(op_name(ops::ABS), vm.arithmetic_mode().abs(v0))?;
+        vm.push(val);
+
+        Ok(())
+    }
+}
+
+fn bitwise_2_arg<C: CellValue>(vm: &mut ShrekVM<C>, func_num: i32) -> VmResult<()> {
+    let name = op_name(func_num);
+    if vm.count() < 2 {
+        return Err(stack_underflow(vm, name, 2));
+    }
+
+    let v0 = vm.pop()?;
+    let v1 = vm.pop()?;
+    match apply_bitwise_2_arg(func_num, v1, v0) {
+        Some(Ok(val)) => vm.push(check_cell_size(val)?),
+        Some(Err(message)) => return Err(invalid_argument(name, &message)),
+        None => {}
+    }
+
+    Ok(())
+}
+
+fn bitwise_1_arg<C: CellValue>(vm: &mut ShrekVM<C>, func_num: i32) -> VmResult<()> {
+    let name = op_name(func_num);
+    if vm.count() < 1 {
+        return Err(stack_underflow(vm, name, 1));
+    }
+
+    let v0 = vm.pop()?;
+    match apply_bitwise_1_arg(func_num, v0) {
+        Some(Ok(val)) => vm.push(val),
+        Some(Err(message)) => return Err(invalid_argument(name, &message)),
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Replace the address `{0}` with the value stored there.
+fn load<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    if vm.count() < 1 {
+        return Err(stack_underflow(vm, op_name(ops::LOAD), 1));
+    }
+
+    let index = memory_index(vm, &vm.peek()?)?;
+    vm.pop()?;
+    let val = vm.memory()[index].clone();
+    vm.push(val);
+
+    Ok(())
+}
+
+/// Pop the address `{0}` and the value `{1}`, and store the value at the address.
+fn store<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    if vm.count() < 2 {
+        return Err(stack_underflow(vm, op_name(ops::STORE), 2));
+    }
+
+    let index = memory_index(vm, &vm.peek()?)?;
+    vm.pop()?;
+    let val = vm.pop()?;
+    vm.memory_mut()[index] = val;
+
+    Ok(())
+}
+
+/// Push the number of cells of memory.
+fn memory_size<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    let size = size_value(vm.memory().len());
+    vm.push(size);
+
+    Ok(())
+}
+
+/// Pop n and add n cells, set to 0, to the end of memory. Pushes the size of memory before it was grown, which is
+/// the address of the first new cell.
+fn memory_grow<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    if vm.count() < 1 {
+        return Err(stack_underflow(vm, op_name(ops::MEMORY_GROW), 1));
+    }
+    let n = count_value(op_name(ops::MEMORY_GROW), &vm.peek()?)?;
+
+    let old_size = vm.memory().len();
+    let new_size = old_size.saturating_add(n);
+    if let Some(max) = vm.limits().max_memory {
+        if new_size > max {
+            return Err(ErrorKind::LimitExceeded(Limit::Memory).into());
+        }
+    }
+
+    // Checked before allocating. Without a limit, a huge request could otherwise be killed by the host for using up
+    // its memory.
+    let bytes = new_size.saturating_mul(std::mem::size_of::<C>());
+    if bytes > MAX_MEMORY_BYTES || vm.memory_mut().try_reserve_exact(n).is_err() {
+        return Err(ErrorKind::LimitExceeded(Limit::Memory).into());
+    }
+
+    vm.pop()?;
+    vm.memory_mut().resize(new_size, C::default());
+    vm.push(size_value(old_size));
+
+    Ok(())
+}
+
+/// Replace the slot number `{0}` with the value of that global.
+fn get_global<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    if vm.count() < 1 {
+        return Err(stack_underflow(vm, op_name(ops::GET_GLOBAL), 1));
+    }
+
+    let index = global_index(op_name(ops::GET_GLOBAL), &vm.peek()?)?;
+    vm.pop()?;
+    let val = vm.globals()[index].clone();
+    vm.push(val);
+
+    Ok(())
+}
+
+/// Pop the slot number `{0}` and the value `{1}`, and set the global to the value.
+fn set_global<C: CellValue>(vm: &mut ShrekVM<C>) -> VmResult<()> {
+    if vm.count() < 2 {
+        return Err(stack_underflow(vm, op_name(ops::SET_GLOBAL), 2));
+    }
+
+    let index = global_index(op_name(ops::SET_GLOBAL), &vm.peek()?)?;
+    vm.pop()?;
+    let val = vm.pop()?;
+    vm.globals_mut()[index] = val;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::arithmetic::ArithmeticMode;
+    use crate::limits::Limits;
+    use num_bigint::BigInt;
+    use std::io;
     use std::vec::Vec;
 
     #[test]
     fn test_input() {
-        let input_mock = || Some("asdf".to_string());
-        let disp_mock = || -> VmResult<()> { Ok(()) };
+        let mut output_buf = Vec::new();
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), &b"asdf\n"[..], &mut output_buf);
 
-        let mut vm = ShrekVM::new(Vec::new());
-
-        input(&mut vm, input_mock, disp_mock).unwrap();
+        input(&mut vm).unwrap();
 
         assert_eq!('a' as i32, vm.pop().unwrap());
         assert_eq!('s' as i32, vm.pop().unwrap());
@@ -251,24 +998,51 @@ mod tests {
         assert_eq!(0, vm.pop().unwrap());
 
         assert_eq!(0, vm.count());
+
+        drop(vm);
+        assert_eq!(b"input: ", &output_buf[..]);
     }
 
     #[test]
-    fn test_output() {
-        let disp_mock = |_| ();
+    fn test_input_no_prompt() {
+        let mut output_buf = Vec::new();
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), &b"\n"[..], &mut output_buf);
+        vm.set_prompt(false);
+
+        input(&mut vm).unwrap();
+        assert_eq!(vec![0], vm.stack());
+
+        drop(vm);
+        assert!(output_buf.is_empty());
+    }
 
-        let mut vm = ShrekVM::new(Vec::new());
+    #[test]
+    fn test_input_eof() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), &b"a"[..], io::sink());
+
+        input(&mut vm).unwrap();
+        input(&mut vm).unwrap();
+        assert_eq!(vec![0, 'a' as i32, EOF], vm.stack());
+    }
+
+    #[test]
+    fn test_output() {
+        let mut output_buf = Vec::new();
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), &mut output_buf);
         vm.push(123);
 
-        output(&mut vm, disp_mock).unwrap();
+        output(&mut vm).unwrap();
 
         assert_eq!(123, vm.peek().unwrap());
         assert_eq!(1, vm.count());
+
+        drop(vm);
+        assert_eq!(b"123\n", &output_buf[..]);
     }
 
     #[test]
     fn test_add() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
 
         vm.push(1);
         vm.push(3);
@@ -282,14 +1056,14 @@ mod tests {
 
     #[test]
     fn test_add_bad_stack() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
         vm.push(3);
         assert!(add(&mut vm).is_err());
     }
 
     #[test]
     fn test_subtract() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
 
         vm.push(1);
         vm.push(3);
@@ -303,14 +1077,14 @@ mod tests {
 
     #[test]
     fn test_subtract_bad_stack() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
         vm.push(3);
         assert!(subtract(&mut vm).is_err());
     }
 
     #[test]
     fn test_multiply() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
 
         vm.push(8);
         vm.push(3);
@@ -324,14 +1098,14 @@ mod tests {
 
     #[test]
     fn test_multiply_bad_stack() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
         vm.push(3);
         assert!(multiply(&mut vm).is_err());
     }
 
     #[test]
     fn test_divide() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
 
         vm.push(13);
         vm.push(3);
@@ -345,14 +1119,14 @@ mod tests {
 
     #[test]
     fn test_divide_bad_stack() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
         vm.push(3);
         assert!(divide(&mut vm).is_err());
     }
 
     #[test]
     fn test_mod_() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
 
         vm.push(13);
         vm.push(3);
@@ -366,14 +1140,14 @@ mod tests {
 
     #[test]
     fn test_mod_bad_stack() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
         vm.push(3);
         assert!(mod_(&mut vm).is_err());
     }
 
     #[test]
     fn test_double_val() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
 
         vm.push(3);
 
@@ -386,13 +1160,13 @@ mod tests {
 
     #[test]
     fn test_double_bad_stack() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
         assert!(double_val(&mut vm).is_err());
     }
 
     #[test]
     fn test_negate() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
 
         vm.push(3);
 
@@ -405,13 +1179,13 @@ mod tests {
 
     #[test]
     fn test_negate_bad_stack() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
         assert!(negate(&mut vm).is_err());
     }
 
     #[test]
     fn test_square() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
 
         vm.push(4);
 
@@ -424,13 +1198,13 @@ mod tests {
 
     #[test]
     fn test_square_bad_stack() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
         assert!(square(&mut vm).is_err());
     }
 
     #[test]
     fn test_clone() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
 
         vm.push(3);
 
@@ -443,7 +1217,517 @@ mod tests {
 
     #[test]
     fn test_clone_bad_stack() {
-        let mut vm = ShrekVM::new(Vec::new());
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
         assert!(clone(&mut vm).is_err());
     }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
+
+        vm.push(13);
+        vm.push(0);
+
+        let err = divide(&mut vm).unwrap_err();
+        assert_eq!("division by zero in divide", err.message);
+    }
+
+    #[test]
+    fn test_mod_by_zero() {
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
+        vm.set_arithmetic_mode(ArithmeticMode::Wrapping);
+
+        vm.push(13);
+        vm.push(0);
+
+        assert!(mod_(&mut vm).is_err());
+    }
+
+    #[test]
+    fn test_checked_overflow() {
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
+
+        vm.push(i32::MAX);
+        vm.push(1);
+
+        let err = add(&mut vm).unwrap_err();
+        assert_eq!("integer overflow in add", err.message);
+    }
+
+    #[test]
+    fn test_wrapping_overflow() {
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
+        vm.set_arithmetic_mode(ArithmeticMode::Wrapping);
+
+        vm.push(i32::MAX);
+        double_val(&mut vm).unwrap();
+
+        assert_eq!(-2, vm.peek().unwrap());
+    }
+
+    #[test]
+    fn test_saturating_overflow() {
+        let mut vm = ShrekVM::<i32>::new(Vec::new());
+        vm.set_arithmetic_mode(ArithmeticMode::Saturating);
+
+        vm.push(-100_000);
+        square(&mut vm).unwrap();
+
+        assert_eq!(i32::MAX, vm.peek().unwrap());
+    }
+
+    #[test]
+    fn test_output_char() {
+        let mut output_buf = Vec::new();
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), &mut output_buf);
+
+        for value in ['S' as i32, 0x1F9CC, -1, 0xD800] {
+            vm.push(value);
+            output_char(&mut vm).unwrap();
+        }
+        assert_eq!(4, vm.count());
+
+        drop(vm);
+        assert_eq!(
+            "S\u{1F9CC}\u{FFFD}\u{FFFD}",
+            String::from_utf8(output_buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_output_byte() {
+        let mut output_buf = Vec::new();
+        let mut vm = ShrekVM::<i64>::with_io(Vec::new(), io::empty(), &mut output_buf);
+
+        for value in [65, 0x1ff, -1] {
+            vm.push(value);
+            output_byte(&mut vm).unwrap();
+        }
+        assert_eq!(3, vm.count());
+
+        drop(vm);
+        assert_eq!(b"A\xff\xff", &output_buf[..]);
+    }
+
+    #[test]
+    fn test_output_string() {
+        let mut output_buf = Vec::new();
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), &b"ogre \xc3\xa9\n"[..], &mut output_buf);
+        vm.set_prompt(false);
+        vm.push(7);
+
+        input(&mut vm).unwrap();
+        output_string(&mut vm).unwrap();
+        assert_eq!(vec![7], vm.stack());
+
+        let err = output_string(&mut vm).unwrap_err();
+        assert_eq!(ErrorKind::StackUnderflow { needed: 2, had: 1 }, err.kind);
+        assert_eq!(vec![7], vm.stack());
+
+        drop(vm);
+        assert_eq!("ogre \u{e9}", String::from_utf8(output_buf).unwrap());
+    }
+
+    #[test]
+    fn test_input_byte() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), &b"a\n"[..], io::sink());
+
+        for _ in 0..3 {
+            input_byte(&mut vm).unwrap();
+        }
+        assert_eq!(vec!['a' as i32, '\n' as i32, EOF], vm.stack());
+    }
+
+    #[test]
+    fn test_input_int() {
+        let mut output_buf = Vec::new();
+        let input = b" -42 \n9999999999\n";
+        let mut vm = ShrekVM::<i64>::with_io(Vec::new(), &input[..], &mut output_buf);
+
+        input_int(&mut vm).unwrap();
+        input_int(&mut vm).unwrap();
+        input_int(&mut vm).unwrap();
+        assert_eq!(vec![-42, 1, 9999999999, 1, 0], vm.stack());
+
+        drop(vm);
+        assert_eq!(b"input: input: input: ", &output_buf[..]);
+    }
+
+    #[test]
+    fn test_input_int_invalid() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), &b"9999999999\nabc\n"[..], io::sink());
+
+        let err = input_int(&mut vm).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind);
+        let err = input_int(&mut vm).unwrap_err();
+        assert_eq!("\"abc\" is not an integer", err.message);
+        assert_eq!(0, vm.count());
+    }
+
+    #[test]
+    fn test_input_all() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), &b"a\nb\n"[..], io::sink());
+
+        input_all(&mut vm).unwrap();
+        assert_eq!(
+            vec![0, '\n' as i32, 'b' as i32, '\n' as i32, 'a' as i32],
+            vm.stack()
+        );
+
+        input_all(&mut vm).unwrap();
+        assert_eq!(0, vm.pop().unwrap());
+    }
+
+    #[test]
+    fn test_stack_ops() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+        vm.stack_mut().extend(&[1, 2, 3]);
+
+        swap(&mut vm).unwrap();
+        assert_eq!(vec![1, 3, 2], vm.stack());
+        over(&mut vm).unwrap();
+        assert_eq!(vec![1, 3, 2, 3], vm.stack());
+        rot(&mut vm).unwrap();
+        assert_eq!(vec![1, 2, 3, 3], vm.stack());
+
+        vm.push(3);
+        pick(&mut vm).unwrap();
+        assert_eq!(vec![1, 2, 3, 3, 1], vm.stack());
+        vm.push(4);
+        roll(&mut vm).unwrap();
+        assert_eq!(vec![2, 3, 3, 1, 1], vm.stack());
+
+        depth(&mut vm).unwrap();
+        assert_eq!(vec![2, 3, 3, 1, 1, 5], vm.stack());
+        vm.push(4);
+        drop_n(&mut vm).unwrap();
+        assert_eq!(vec![2, 3], vm.stack());
+
+        clear(&mut vm).unwrap();
+        assert_eq!(0, vm.count());
+    }
+
+    #[test]
+    fn test_stack_op_errors() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+        vm.stack_mut().extend(&[1, 2]);
+
+        vm.push(2);
+        let err = pick(&mut vm).unwrap_err();
+        assert_eq!(ErrorKind::StackUnderflow { needed: 4, had: 3 }, err.kind);
+        assert_eq!(vec![1, 2, 2], vm.stack());
+
+        vm.push(-1);
+        let err = drop_n(&mut vm).unwrap_err();
+        assert_eq!(ErrorKind::InvalidArgument, err.kind);
+        assert_eq!(vec![1, 2, 2, -1], vm.stack());
+
+        clear(&mut vm).unwrap();
+        let err = rot(&mut vm).unwrap_err();
+        assert_eq!(ErrorKind::StackUnderflow { needed: 3, had: 0 }, err.kind);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+
+        let cases = [
+            (ops::EQ, 2, 2, 1),
+            (ops::NE, 2, 2, 0),
+            (ops::LT, 1, 2, 1),
+            (ops::LE, 2, 2, 1),
+            (ops::GT, 1, 2, 0),
+            (ops::GE, -1, -2, 1),
+            (ops::AND, 5, 0, 0),
+            (ops::AND, 5, -3, 1),
+            (ops::OR, 0, 7, 1),
+            (ops::OR, 0, 0, 0),
+            (ops::MIN, 3, -4, -4),
+            (ops::MAX, 3, -4, 3),
+        ];
+        for (func_num, v1, v0, expected) in cases.iter() {
+            vm.push(*v1);
+            vm.push(*v0);
+            execute_builtin(&mut vm, *func_num).unwrap();
+            assert_eq!(
+                *expected,
+                vm.pop().unwrap(),
+                "func {} on {} {}",
+                func_num,
+                v1,
+                v0
+            );
+            assert_eq!(0, vm.count());
+        }
+    }
+
+    #[test]
+    fn test_logic_1_arg() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+
+        let cases = [
+            (ops::NOT, 0, 1),
+            (ops::NOT, -2, 0),
+            (ops::ABS, -7, 7),
+            (ops::SIGN, -7, -1),
+            (ops::SIGN, 0, 0),
+            (ops::SIGN, 9, 1),
+        ];
+        for (func_num, v0, expected) in cases.iter() {
+            vm.push(*v0);
+            execute_builtin(&mut vm, *func_num).unwrap();
+            assert_eq!(*expected, vm.pop().unwrap(), "func {} on {}", func_num, v0);
+        }
+
+        vm.push(i32::MIN);
+        let err = abs(&mut vm).unwrap_err();
+        assert_eq!(ErrorKind::Overflow, err.kind);
+
+        let err = execute_builtin(&mut vm, ops::LT).unwrap_err();
+        assert_eq!(ErrorKind::StackUnderflow { needed: 2, had: 0 }, err.kind);
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+
+        let cases = [
+            (ops::BIT_AND, 0b1100, 0b1010, 0b1000),
+            (ops::BIT_OR, 0b1100, 0b1010, 0b1110),
+            (ops::BIT_XOR, 0b1100, 0b1010, 0b0110),
+            (ops::SHIFT_LEFT, 3, 4, 48),
+            (ops::SHIFT_LEFT, 3, 32, 0),
+            (ops::SHIFT_RIGHT, -48, 4, -3),
+            (ops::SHIFT_RIGHT, -48, 100, -1),
+            (ops::SHIFT_RIGHT_LOGICAL, -1, 28, 0xf),
+            (ops::SHIFT_RIGHT_LOGICAL, -1, 32, 0),
+        ];
+        for (func_num, v1, v0, expected) in cases.iter() {
+            vm.push(*v1);
+            vm.push(*v0);
+            execute_builtin(&mut vm, *func_num).unwrap();
+            assert_eq!(
+                *expected,
+                vm.pop().unwrap(),
+                "func {} on {} {}",
+                func_num,
+                v1,
+                v0
+            );
+        }
+
+        let cases = [
+            (ops::BIT_NOT, 0, -1),
+            (ops::POPCOUNT, 0xff, 8),
+            (ops::POPCOUNT, -1, 32),
+            (ops::LEADING_ZEROS, 1, 31),
+            (ops::LEADING_ZEROS, 0, 32),
+        ];
+        for (func_num, v0, expected) in cases.iter() {
+            vm.push(*v0);
+            execute_builtin(&mut vm, *func_num).unwrap();
+            assert_eq!(*expected, vm.pop().unwrap(), "func {} on {}", func_num, v0);
+        }
+    }
+
+    #[test]
+    fn test_bitwise_errors() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+        vm.push(1);
+        vm.push(-1);
+        let err = execute_builtin(&mut vm, ops::SHIFT_LEFT).unwrap_err();
+        assert_eq!(ErrorKind::InvalidArgument, err.kind);
+        assert_eq!(
+            "shift left: shift count must be 0 or more, got -1",
+            err.message
+        );
+
+        let mut vm = ShrekVM::<BigInt>::with_io(Vec::new(), io::empty(), io::sink());
+        vm.push(BigInt::from(-1));
+        let err = execute_builtin(&mut vm, ops::POPCOUNT).unwrap_err();
+        assert_eq!(ErrorKind::InvalidArgument, err.kind);
+
+        // Big counts are not clamped for a type with no fixed width, they are rejected.
+        vm.stack_mut().clear();
+        vm.push(BigInt::from(1));
+        vm.push(BigInt::from(1) << 40u32);
+        let err = execute_builtin(&mut vm, ops::SHIFT_LEFT).unwrap_err();
+        assert_eq!(ErrorKind::InvalidArgument, err.kind);
+        vm.stack_mut().clear();
+        vm.push(BigInt::from(1));
+        vm.push(BigInt::from(MAX_UNBOUNDED_SHIFT + 1));
+        let err = execute_builtin(&mut vm, ops::SHIFT_LEFT).unwrap_err();
+        assert_eq!(ErrorKind::InvalidArgument, err.kind);
+
+        vm.stack_mut().clear();
+        vm.push(BigInt::from(1));
+        vm.push(BigInt::from(MAX_UNBOUNDED_SHIFT - 1));
+        execute_builtin(&mut vm, ops::SHIFT_LEFT).unwrap();
+        assert_eq!(
+            BigInt::from(1) << (MAX_UNBOUNDED_SHIFT - 1),
+            vm.peek().unwrap()
+        );
+
+        // The result would be one bit longer than a cell can be.
+        vm.push(BigInt::from(1));
+        let err = execute_builtin(&mut vm, ops::SHIFT_LEFT).unwrap_err();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::CellSize), err.kind);
+
+        // Fixed width types shift every bit out, however big the count is.
+        let mut vm = ShrekVM::<i64>::with_io(Vec::new(), io::empty(), io::sink());
+        vm.push(1);
+        vm.push(1 << 40);
+        execute_builtin(&mut vm, ops::SHIFT_LEFT).unwrap();
+        assert_eq!(0, vm.pop().unwrap());
+    }
+
+    #[test]
+    fn test_cell_size_limit() {
+        let mut vm = ShrekVM::<BigInt>::with_io(Vec::new(), io::empty(), io::sink());
+        vm.push(BigInt::from(3));
+
+        // Each square doubles the number of bits, so this stops well before the loop ends.
+        let err = (0..32)
+            .map(|_| execute_builtin(&mut vm, ops::SQUARE))
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::CellSize), err.kind);
+
+        vm.push(BigInt::from(1) << (MAX_CELL_BITS - 1));
+        execute_builtin(&mut vm, ops::NEGATE).unwrap();
+        let err = execute_builtin(&mut vm, ops::DOUBLE_VAL).unwrap_err();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::CellSize), err.kind);
+
+        // Fixed width types overflow long before the limit.
+        let mut vm = ShrekVM::<i64>::with_io(Vec::new(), io::empty(), io::sink());
+        vm.push(i64::MAX);
+        let err = execute_builtin(&mut vm, ops::SQUARE).unwrap_err();
+        assert_eq!(ErrorKind::Overflow, err.kind);
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+
+        memory_size(&mut vm).unwrap();
+        assert_eq!(0, vm.pop().unwrap());
+
+        vm.push(3);
+        memory_grow(&mut vm).unwrap();
+        assert_eq!(0, vm.pop().unwrap());
+        vm.push(2);
+        memory_grow(&mut vm).unwrap();
+        assert_eq!(3, vm.pop().unwrap());
+        assert_eq!(vec![0; 5], vm.memory());
+
+        vm.push(42);
+        vm.push(4);
+        store(&mut vm).unwrap();
+        assert_eq!(0, vm.count());
+
+        vm.push(4);
+        load(&mut vm).unwrap();
+        assert_eq!(vec![42], vm.stack());
+        assert_eq!(vec![0, 0, 0, 0, 42], vm.memory());
+
+        vm.reset();
+        assert!(vm.memory().is_empty());
+    }
+
+    #[test]
+    fn test_memory_errors() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+        vm.memory_mut().resize(2, 0);
+
+        vm.push(7);
+        vm.push(2);
+        let err = store(&mut vm).unwrap_err();
+        assert_eq!(
+            ErrorKind::MemoryOutOfBounds {
+                address: 2,
+                size: 2
+            },
+            err.kind
+        );
+        assert_eq!(vec![7, 2], vm.stack());
+
+        vm.push(-1);
+        let err = load(&mut vm).unwrap_err();
+        assert_eq!(
+            ErrorKind::MemoryOutOfBounds {
+                address: -1,
+                size: 2
+            },
+            err.kind
+        );
+
+        vm.set_limits(Limits {
+            max_memory: Some(4),
+            ..Limits::default()
+        });
+        vm.push(3);
+        let err = memory_grow(&mut vm).unwrap_err();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::Memory), err.kind);
+        assert_eq!(2, vm.memory().len());
+    }
+
+    #[test]
+    fn test_memory_grow_past_max_bytes() {
+        // No limits are set, so only MAX_MEMORY_BYTES stops the grow. It is checked before anything is allocated.
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+        let max_cells = (MAX_MEMORY_BYTES / 4) as i32;
+        vm.memory_mut().resize(4, 0);
+        vm.push(max_cells - 3);
+
+        let err = memory_grow(&mut vm).unwrap_err();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::Memory), err.kind);
+        assert_eq!(4, vm.memory().len());
+        assert_eq!(vec![max_cells - 3], vm.stack());
+
+        let mut vm = ShrekVM::<BigInt>::with_io(Vec::new(), io::empty(), io::sink());
+        vm.push(BigInt::from(i32::MAX));
+        let err = memory_grow(&mut vm).unwrap_err();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::Memory), err.kind);
+        assert!(vm.memory().is_empty());
+    }
+
+    #[test]
+    fn test_globals() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+        assert_eq!(vec![0; GLOBAL_SLOTS], vm.globals());
+
+        vm.push(42);
+        vm.push(3);
+        set_global(&mut vm).unwrap();
+        assert_eq!(0, vm.count());
+
+        vm.push(3);
+        get_global(&mut vm).unwrap();
+        assert_eq!(vec![42], vm.stack());
+
+        vm.push(64);
+        let err = get_global(&mut vm).unwrap_err();
+        assert_eq!(ErrorKind::InvalidArgument, err.kind);
+        assert_eq!(vec![42, 64], vm.stack());
+
+        vm.reset();
+        assert_eq!(vec![0; GLOBAL_SLOTS], vm.globals());
+    }
+
+    #[test]
+    fn test_builtin_name() {
+        assert_eq!(Some("input"), builtin_name(ops::INPUT));
+        assert_eq!(
+            Some("logical shift right"),
+            builtin_name(ops::SHIFT_RIGHT_LOGICAL)
+        );
+        assert_eq!(Some("set global"), builtin_name(ops::SET_GLOBAL));
+        assert_eq!(None, builtin_name(ops::SET_GLOBAL + 1));
+        assert_eq!(None, builtin_name(-1));
+
+        // Error messages use the same names.
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+        let err = execute_builtin(&mut vm, ops::DOUBLE_VAL).unwrap_err();
+        assert_eq!("double requires 1 item on the stack", err.message);
+    }
 }
@@ -1,3 +1,5 @@
+use crate::span::Span;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)] // Allow NoOp to be dead. This op may be generated in the future.
 pub enum OpCode {
@@ -15,4 +17,24 @@ pub enum OpCode {
 pub struct ByteCode {
     pub op_code: OpCode,
     pub arg: i32,
+    /// The source code this operation was generated from, if known.
+    pub span: Option<Span>,
+}
+
+impl ByteCode {
+    pub fn new(op_code: OpCode, arg: i32) -> ByteCode {
+        ByteCode {
+            op_code,
+            arg,
+            span: None,
+        }
+    }
+
+    pub fn with_span(op_code: OpCode, arg: i32, span: Span) -> ByteCode {
+        ByteCode {
+            op_code,
+            arg,
+            span: Some(span),
+        }
+    }
 }
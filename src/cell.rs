@@ -0,0 +1,419 @@
+//! The types a VM can use for the values on its stack.
+//!
+//! `ShrekVM`, the builtins and the optimizer are generic over [`CellValue`]. Embedding programs pick the type when
+//! they create a VM, for example `ShrekVM::<i64>::with_io(...)`. [`CellType`] names the supported types so the type
+//! can also be chosen at run time, which is what the `--cell` option does.
+
+use num_bigint::BigInt;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// A value that can be stored on the VM's stack.
+///
+/// The checked operations return `None` when the result does not fit in the type. Division by zero is handled before
+/// these are called, so `div` and `rem` are never given a zero divisor.
+pub trait CellValue: Clone + Default + Ord + fmt::Debug + fmt::Display + FromStr + 'static {
+    fn from_i32(value: i32) -> Self;
+
+    /// Convert to an `i32`, if the value fits.
+    fn to_i32(&self) -> Option<i32>;
+
+    /// Number of bits in the type. `None` for a type with no fixed width.
+    fn bits() -> Option<u32>;
+
+    /// Number of bits used by this value. For a fixed width type, this is the width.
+    fn bit_len(&self) -> u64;
+
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+    fn checked_sub(self, rhs: Self) -> Option<Self>;
+    fn checked_mul(self, rhs: Self) -> Option<Self>;
+    fn checked_div(self, rhs: Self) -> Option<Self>;
+    fn checked_rem(self, rhs: Self) -> Option<Self>;
+    fn checked_neg(self) -> Option<Self>;
+
+    fn wrapping_add(self, rhs: Self) -> Self;
+    fn wrapping_sub(self, rhs: Self) -> Self;
+    fn wrapping_mul(self, rhs: Self) -> Self;
+    fn wrapping_div(self, rhs: Self) -> Self;
+    fn wrapping_rem(self, rhs: Self) -> Self;
+    fn wrapping_neg(self) -> Self;
+
+    fn saturating_add(self, rhs: Self) -> Self;
+    fn saturating_sub(self, rhs: Self) -> Self;
+    fn saturating_mul(self, rhs: Self) -> Self;
+    fn saturating_div(self, rhs: Self) -> Self;
+    fn saturating_neg(self) -> Self;
+
+    // Bitwise operations use two's complement. Big integers act as if they have an infinite number of sign bits.
+    fn bit_and(self, rhs: Self) -> Self;
+    fn bit_or(self, rhs: Self) -> Self;
+    fn bit_xor(self, rhs: Self) -> Self;
+    fn bit_not(self) -> Self;
+
+    /// Shift left. Shifting a fixed width type by its width or more gives 0.
+    fn shl(self, n: u32) -> Self;
+    /// Arithmetic shift right, rounding toward negative infinity. Shifting a fixed width type by its width or more
+    /// gives 0 or -1.
+    fn shr(self, n: u32) -> Self;
+    /// Logical shift right, filling with zeros. Shifting a fixed width type by its width or more gives 0. `None` for
+    /// negative values of a type with no fixed width.
+    fn shr_logical(self, n: u32) -> Option<Self>;
+    /// Number of one bits. `None` for negative values of a type with no fixed width.
+    fn count_ones(&self) -> Option<u32>;
+    /// Number of zero bits above the highest one bit. `None` for a type with no fixed width.
+    fn leading_zeros(&self) -> Option<u32>;
+
+    fn is_zero(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn is_negative(&self) -> bool {
+        *self < Self::default()
+    }
+
+    /// 1 for true and 0 for false.
+    fn from_bool(value: bool) -> Self {
+        Self::from_i32(value as i32)
+    }
+
+    /// Convert to an `i32`, clamping values that do not fit. Used for exit codes and error details.
+    fn to_i32_saturating(&self) -> i32 {
+        match self.to_i32() {
+            Some(x) => x,
+            None if self.is_negative() => i32::MIN,
+            None => i32::MAX,
+        }
+    }
+}
+
+/// The supported stack value types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellType {
+    /// 32 bit signed integers.
+    #[default]
+    I32,
+    /// 64 bit signed integers.
+    I64,
+    /// Arbitrary precision integers. These never overflow.
+    BigInt,
+}
+
+macro_rules! impl_primitive_cell {
+    ($t:ty, $unsigned:ty) => {
+        impl CellValue for $t {
+            fn from_i32(value: i32) -> Self {
+                <$t>::from(value)
+            }
+
+            fn to_i32(&self) -> Option<i32> {
+                i32::try_from(*self).ok()
+            }
+
+            fn bits() -> Option<u32> {
+                Some(<$t>::BITS)
+            }
+
+            fn bit_len(&self) -> u64 {
+                u64::from(<$t>::BITS)
+            }
+
+            fn checked_add(self, rhs: Self) -> Option<Self> {
+                <$t>::checked_add(self, rhs)
+            }
+
+            fn checked_sub(self, rhs: Self) -> Option<Self> {
+                <$t>::checked_sub(self, rhs)
+            }
+
+            fn checked_mul(self, rhs: Self) -> Option<Self> {
+                <$t>::checked_mul(self, rhs)
+            }
+
+            fn checked_div(self, rhs: Self) -> Option<Self> {
+                <$t>::checked_div(self, rhs)
+            }
+
+            fn checked_rem(self, rhs: Self) -> Option<Self> {
+                <$t>::checked_rem(self, rhs)
+            }
+
+            fn checked_neg(self) -> Option<Self> {
+                <$t>::checked_neg(self)
+            }
+
+            fn wrapping_add(self, rhs: Self) -> Self {
+                <$t>::wrapping_add(self, rhs)
+            }
+
+            fn wrapping_sub(self, rhs: Self) -> Self {
+                <$t>::wrapping_sub(self, rhs)
+            }
+
+            fn wrapping_mul(self, rhs: Self) -> Self {
+                <$t>::wrapping_mul(self, rhs)
+            }
+
+            fn wrapping_div(self, rhs: Self) -> Self {
+                <$t>::wrapping_div(self, rhs)
+            }
+
+            fn wrapping_rem(self, rhs: Self) -> Self {
+                <$t>::wrapping_rem(self, rhs)
+            }
+
+            fn wrapping_neg(self) -> Self {
+                <$t>::wrapping_neg(self)
+            }
+
+            fn saturating_add(self, rhs: Self) -> Self {
+                <$t>::saturating_add(self, rhs)
+            }
+
+            fn saturating_sub(self, rhs: Self) -> Self {
+                <$t>::saturating_sub(self, rhs)
+            }
+
+            fn saturating_mul(self, rhs: Self) -> Self {
+                <$t>::saturating_mul(self, rhs)
+            }
+
+            fn saturating_div(self, rhs: Self) -> Self {
+                <$t>::saturating_div(self, rhs)
+            }
+
+            fn saturating_neg(self) -> Self {
+                <$t>::saturating_neg(self)
+            }
+
+            fn bit_and(self, rhs: Self) -> Self {
+                self & rhs
+            }
+
+            fn bit_or(self, rhs: Self) -> Self {
+                self | rhs
+            }
+
+            fn bit_xor(self, rhs: Self) -> Self {
+                self ^ rhs
+            }
+
+            fn bit_not(self) -> Self {
+                !self
+            }
+
+            fn shl(self, n: u32) -> Self {
+                self.checked_shl(n).unwrap_or(0)
+            }
+
+            fn shr(self, n: u32) -> Self {
+                self.checked_shr(n).unwrap_or(if self < 0 { -1 } else { 0 })
+            }
+
+            fn shr_logical(self, n: u32) -> Option<Self> {
+                Some((self as $unsigned).checked_shr(n).unwrap_or(0) as $t)
+            }
+
+            fn count_ones(&self) -> Option<u32> {
+                Some(<$t>::count_ones(*self))
+            }
+
+            fn leading_zeros(&self) -> Option<u32> {
+                Some(<$t>::leading_zeros(*self))
+            }
+        }
+    };
+}
+
+impl_primitive_cell!(i32, u32);
+impl_primitive_cell!(i64, u64);
+
+// Big integers cannot overflow, so every arithmetic mode gives the exact result.
+impl CellValue for BigInt {
+    fn from_i32(value: i32) -> Self {
+        BigInt::from(value)
+    }
+
+    fn to_i32(&self) -> Option<i32> {
+        i32::try_from(self).ok()
+    }
+
+    fn bits() -> Option<u32> {
+        None
+    }
+
+    fn bit_len(&self) -> u64 {
+        BigInt::bits(self)
+    }
+
+    fn checked_add(self, rhs: Self) -> Option<Self> {
+        Some(self + rhs)
+    }
+
+    fn checked_sub(self, rhs: Self) -> Option<Self> {
+        Some(self - rhs)
+    }
+
+    fn checked_mul(self, rhs: Self) -> Option<Self> {
+        Some(self * rhs)
+    }
+
+    fn checked_div(self, rhs: Self) -> Option<Self> {
+        Some(self / rhs)
+    }
+
+    fn checked_rem(self, rhs: Self) -> Option<Self> {
+        Some(self % rhs)
+    }
+
+    fn checked_neg(self) -> Option<Self> {
+        Some(-self)
+    }
+
+    fn wrapping_add(self, rhs: Self) -> Self {
+        self + rhs
+    }
+
+    fn wrapping_sub(self, rhs: Self) -> Self {
+        self - rhs
+    }
+
+    fn wrapping_mul(self, rhs: Self) -> Self {
+        self * rhs
+    }
+
+    fn wrapping_div(self, rhs: Self) -> Self {
+        self / rhs
+    }
+
+    fn wrapping_rem(self, rhs: Self) -> Self {
+        self % rhs
+    }
+
+    fn wrapping_neg(self) -> Self {
+        -self
+    }
+
+    fn saturating_add(self, rhs: Self) -> Self {
+        self + rhs
+    }
+
+    fn saturating_sub(self, rhs: Self) -> Self {
+        self - rhs
+    }
+
+    fn saturating_mul(self, rhs: Self) -> Self {
+        self * rhs
+    }
+
+    fn saturating_div(self, rhs: Self) -> Self {
+        self / rhs
+    }
+
+    fn saturating_neg(self) -> Self {
+        -self
+    }
+
+    fn bit_and(self, rhs: Self) -> Self {
+        self & rhs
+    }
+
+    fn bit_or(self, rhs: Self) -> Self {
+        self | rhs
+    }
+
+    fn bit_xor(self, rhs: Self) -> Self {
+        self ^ rhs
+    }
+
+    fn bit_not(self) -> Self {
+        !self
+    }
+
+    fn shl(self, n: u32) -> Self {
+        self << n
+    }
+
+    fn shr(self, n: u32) -> Self {
+        self >> n
+    }
+
+    fn shr_logical(self, n: u32) -> Option<Self> {
+        if self.is_negative() {
+            None
+        } else {
+            Some(self >> n)
+        }
+    }
+
+    fn count_ones(&self) -> Option<u32> {
+        if self.is_negative() {
+            None
+        } else {
+            u32::try_from(self.magnitude().count_ones()).ok()
+        }
+    }
+
+    fn leading_zeros(&self) -> Option<u32> {
+        None
+    }
+}
+
+impl FromStr for CellType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "i32" => Ok(CellType::I32),
+            "i64" => Ok(CellType::I64),
+            "bigint" => Ok(CellType::BigInt),
+            _ => Err(format!("unknown cell type {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_i32() {
+        assert_eq!(Some(-5), (-5i64).to_i32());
+        assert_eq!(None, (1i64 << 40).to_i32());
+        assert_eq!(None, (BigInt::from(1) << 40u32).to_i32());
+    }
+
+    #[test]
+    fn test_to_i32_saturating() {
+        assert_eq!(3, BigInt::from(3).to_i32_saturating());
+        assert_eq!(i32::MAX, (1i64 << 40).to_i32_saturating());
+        assert_eq!(i32::MIN, (-(BigInt::from(1) << 40u32)).to_i32_saturating());
+    }
+
+    #[test]
+    fn test_shifts() {
+        assert_eq!(-8, (-1i32).shl(3));
+        assert_eq!(0, 1i32.shl(32));
+        assert_eq!(-1, (-16i32).shr(40));
+        assert_eq!(Some(0x0fff_ffff), (-1i32).shr_logical(4));
+        assert_eq!(Some(0), (-1i64).shr_logical(64));
+        assert_eq!(BigInt::from(1) << 100u32, BigInt::from(1).shl(100));
+        assert_eq!(BigInt::from(-1), BigInt::from(-7).shr(3));
+        assert_eq!(None, BigInt::from(-7).shr_logical(1));
+    }
+
+    #[test]
+    fn test_bit_counts() {
+        assert_eq!(Some(32), CellValue::count_ones(&-1i32));
+        assert_eq!(Some(63), CellValue::leading_zeros(&1i64));
+        assert_eq!(Some(3), BigInt::from(7).count_ones());
+        assert_eq!(None, BigInt::from(-7).count_ones());
+        assert_eq!(None, BigInt::from(7).leading_zeros());
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Ok(CellType::BigInt), "bigint".parse());
+        assert!("i128".parse::<CellType>().is_err());
+    }
+}
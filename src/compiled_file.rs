@@ -0,0 +1,385 @@
+//! Reads and writes compiled programs, so a script can be run without tokenizing, parsing and optimizing it again.
+//!
+//! A compiled file holds a linked [`Program`]. All numbers are little endian:
+//!
+//! ```text
+//! magic           6 bytes    "SHREKC"
+//! version         u16        FORMAT_VERSION
+//! flags           u16        cell type (bits 0-1), arithmetic mode (bits 2-3), has spans (bit 4)
+//! code count      u32
+//! code            op code (u8) and argument (i32) for each instruction
+//! label count     u32
+//! labels          label number (i32), address (u32), name length (u32) and name for each label. A label with no
+//!                 name has a length of u32::MAX.
+//! spans           only if the has spans flag is set. A span for each instruction, then for each label.
+//! ```
+//!
+//! A span is a byte set to 1 followed by the start, end, line and column as u32, or a single 0 byte if the span is not
+//! known.
+
+use crate::arithmetic::ArithmeticMode;
+use crate::byte_code::{ByteCode, OpCode};
+use crate::cell::CellType;
+use crate::linker::LabelEntry;
+use crate::span::Span;
+use crate::Program;
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+/// The first bytes of every compiled file.
+pub const MAGIC: &[u8; 6] = b"SHREKC";
+
+/// Version of the format written by this crate. Files with any other version are rejected.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// File extension used for compiled files.
+pub const EXTENSION: &str = "shrekc";
+
+const FLAG_CELL_TYPE: u16 = 0b11;
+const FLAG_ARITHMETIC_MODE: u16 = 0b1100;
+const FLAG_SPANS: u16 = 0b1_0000;
+
+const NO_NAME: u32 = u32::MAX;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatError {
+    pub message: String,
+}
+
+pub type FormatResult<T> = Result<T, FormatError>;
+
+/// Check whether `bytes` start like a compiled file.
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Serialize `program`. Spans are only written when `include_spans` is set, which makes runtime errors point at the
+/// source code the instruction came from.
+pub fn write(program: &Program, include_spans: bool) -> Vec<u8> {
+    let mut flags =
+        cell_type_bits(program.cell_type) | (arithmetic_mode_bits(program.arithmetic_mode) << 2);
+    if include_spans {
+        flags |= FLAG_SPANS;
+    }
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&flags.to_le_bytes());
+
+    write_u32(&mut bytes, program.byte_code.len());
+    for op in program.byte_code.iter() {
+        bytes.push(op.op_code as u8);
+        bytes.extend_from_slice(&op.arg.to_le_bytes());
+    }
+
+    write_u32(&mut bytes, program.labels.len());
+    for label in program.labels.iter() {
+        bytes.extend_from_slice(&label.label_num.to_le_bytes());
+        write_u32(&mut bytes, label.address);
+        match &label.name {
+            Some(name) => {
+                write_u32(&mut bytes, name.len());
+                bytes.extend_from_slice(name.as_bytes());
+            }
+            None => bytes.extend_from_slice(&NO_NAME.to_le_bytes()),
+        }
+    }
+
+    if include_spans {
+        let code_spans = program.byte_code.iter().map(|op| op.span);
+        let label_spans = program.labels.iter().map(|label| label.span);
+        for span in code_spans.chain(label_spans) {
+            write_span(&mut bytes, span);
+        }
+    }
+
+    bytes
+}
+
+/// Load a program written by [`write`]. Files from a different format version, or with flags this version does not
+/// know, are rejected.
+pub fn read(bytes: &[u8]) -> FormatResult<Program> {
+    if !is_compiled(bytes) {
+        return Err(FormatError::new("not a compiled SHREK file"));
+    }
+
+    let mut reader = Reader {
+        bytes,
+        pos: MAGIC.len(),
+    };
+
+    let version = reader.u16()?;
+    if version != FORMAT_VERSION {
+        return Err(FormatError::new(&format!(
+            "unsupported format version {}, this interpreter reads version {}. Compile the script again.",
+            version, FORMAT_VERSION
+        )));
+    }
+
+    let flags = reader.u16()?;
+    if flags & !(FLAG_CELL_TYPE | FLAG_ARITHMETIC_MODE | FLAG_SPANS) != 0 {
+        return Err(FormatError::new(&format!("unknown flags {:#06x}", flags)));
+    }
+    let cell_type = cell_type_from_bits(flags & FLAG_CELL_TYPE)?;
+    let arithmetic_mode = arithmetic_mode_from_bits((flags & FLAG_ARITHMETIC_MODE) >> 2)?;
+
+    let code_count = reader.count()?;
+    let mut byte_code = Vec::with_capacity(code_count.min(bytes.len()));
+    for _ in 0..code_count {
+        let op_code = op_code_from_u8(reader.u8()?)?;
+        let arg = reader.i32()?;
+        byte_code.push(ByteCode::new(op_code, arg));
+    }
+
+    let label_count = reader.count()?;
+    let mut labels = Vec::with_capacity(label_count.min(bytes.len()));
+    for _ in 0..label_count {
+        let label_num = reader.i32()?;
+        let address = reader.count()?;
+        let name = match reader.u32()? {
+            NO_NAME => None,
+            len => {
+                let name = reader.take(len as usize)?;
+                let name = String::from_utf8(name.to_vec())
+                    .map_err(|_| FormatError::new("label name is not valid UTF-8"))?;
+                Some(name)
+            }
+        };
+        labels.push(LabelEntry {
+            label_num,
+            name,
+            address,
+            span: None,
+        });
+    }
+
+    if flags & FLAG_SPANS != 0 {
+        for op in byte_code.iter_mut() {
+            op.span = reader.span()?;
+        }
+        for label in labels.iter_mut() {
+            label.span = reader.span()?;
+        }
+    }
+
+    if reader.pos != bytes.len() {
+        return Err(FormatError::new(
+            "unexpected data after the end of the program",
+        ));
+    }
+
+    Ok(Program {
+        byte_code,
+        labels,
+        warnings: Vec::new(),
+        arithmetic_mode,
+        cell_type,
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> FormatResult<&'a [u8]> {
+        match self.bytes.get(self.pos..self.pos.saturating_add(len)) {
+            Some(x) => {
+                self.pos += len;
+                Ok(x)
+            }
+            None => Err(FormatError::new("unexpected end of file")),
+        }
+    }
+
+    fn u8(&mut self) -> FormatResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> FormatResult<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> FormatResult<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn i32(&mut self) -> FormatResult<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn count(&mut self) -> FormatResult<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn span(&mut self) -> FormatResult<Option<Span>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(Span::new(
+                self.count()?,
+                self.count()?,
+                self.count()?,
+                self.count()?,
+            ))),
+            x => Err(FormatError::new(&format!("invalid span marker {}", x))),
+        }
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    // Programs and source files with more than u32::MAX items are not supported.
+    let value = u32::try_from(value).expect("value too large for a compiled file");
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_span(bytes: &mut Vec<u8>, span: Option<Span>) {
+    match span {
+        Some(span) => {
+            bytes.push(1);
+            for value in [span.start, span.end, span.line, span.column].iter() {
+                write_u32(bytes, *value);
+            }
+        }
+        None => bytes.push(0),
+    }
+}
+
+fn op_code_from_u8(value: u8) -> FormatResult<OpCode> {
+    match value {
+        2 => Ok(OpCode::Push0),
+        3 => Ok(OpCode::Pop),
+        4 => Ok(OpCode::Bump),
+        5 => Ok(OpCode::Func),
+        6 => Ok(OpCode::Jump),
+        7 => Ok(OpCode::PushConst),
+        // Labels are removed by the linker and no-ops are never generated, so neither appears in a compiled file.
+        _ => Err(FormatError::new(&format!("invalid op code {}", value))),
+    }
+}
+
+fn cell_type_bits(cell_type: CellType) -> u16 {
+    match cell_type {
+        CellType::I32 => 0,
+        CellType::I64 => 1,
+        CellType::BigInt => 2,
+    }
+}
+
+fn cell_type_from_bits(bits: u16) -> FormatResult<CellType> {
+    match bits {
+        0 => Ok(CellType::I32),
+        1 => Ok(CellType::I64),
+        2 => Ok(CellType::BigInt),
+        _ => Err(FormatError::new(&format!("unknown cell type {}", bits))),
+    }
+}
+
+fn arithmetic_mode_bits(mode: ArithmeticMode) -> u16 {
+    match mode {
+        ArithmeticMode::Checked => 0,
+        ArithmeticMode::Wrapping => 1,
+        ArithmeticMode::Saturating => 2,
+    }
+}
+
+fn arithmetic_mode_from_bits(bits: u16) -> FormatResult<ArithmeticMode> {
+    match bits {
+        0 => Ok(ArithmeticMode::Checked),
+        1 => Ok(ArithmeticMode::Wrapping),
+        2 => Ok(ArithmeticMode::Saturating),
+        _ => Err(FormatError::new(&format!(
+            "unknown arithmetic mode {}",
+            bits
+        ))),
+    }
+}
+
+impl FormatError {
+    fn new(message: &str) -> FormatError {
+        FormatError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Compiled File Error: {}", self.message)
+    }
+}
+
+impl Error for FormatError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile_with, CompileOptions};
+
+    fn compile_test_program() -> Program {
+        let options = CompileOptions {
+            cell_type: CellType::I64,
+            arithmetic_mode: ArithmeticMode::Wrapping,
+            ..CompileOptions::default()
+        };
+        compile_with("SRRR !R! SRE SR SRRRE SRK!E! SK!R! !E!", &options).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let program = compile_test_program();
+
+        assert_eq!(program, read(&write(&program, true)).unwrap());
+
+        let stripped = read(&write(&program, false)).unwrap();
+        assert_eq!(program.labels().len(), stripped.labels().len());
+        assert_eq!(Some("!E!"), stripped.labels()[1].name.as_deref());
+        assert!(stripped.byte_code().iter().all(|op| op.span.is_none()));
+        assert_eq!(CellType::I64, stripped.cell_type());
+        assert_eq!(ArithmeticMode::Wrapping, stripped.arithmetic_mode());
+
+        let mut output = Vec::new();
+        stripped.run_with(&b""[..], &mut output).unwrap();
+        assert_eq!(b"3\n2\n1\n", &output[..]);
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let bytes = write(&compile_test_program(), true);
+
+        let err = read(b"SRRR SRE").unwrap_err();
+        assert_eq!("not a compiled SHREK file", err.message);
+
+        let mut other_version = bytes.clone();
+        other_version[6] = 2;
+        let err = read(&other_version).unwrap_err();
+        assert!(err.message.starts_with("unsupported format version 2"));
+
+        let mut unknown_flags = bytes.clone();
+        unknown_flags[9] = 0x80;
+        assert_eq!(
+            "unknown flags 0x8015",
+            read(&unknown_flags).unwrap_err().message
+        );
+
+        let err = read(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!("unexpected end of file", err.message);
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(read(&trailing).is_err());
+
+        // The op code of the first instruction follows the header and the code count.
+        for op_code in [OpCode::NoOp, OpCode::Label].iter() {
+            let mut invalid_op = bytes.clone();
+            invalid_op[14] = *op_code as u8;
+            let err = read(&invalid_op).unwrap_err();
+            assert_eq!(format!("invalid op code {}", *op_code as u8), err.message);
+        }
+    }
+}
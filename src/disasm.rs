@@ -0,0 +1,161 @@
+//! Listings of linked byte code, for checking what the code generator and optimizer produced.
+//!
+//! Each instruction is shown with its address, op code and argument, followed by notes and the source it came from:
+//!
+//! ```text
+//! !R!
+//!    1  PushConst  1        output          2:5 SR
+//!    2  Func       0                        2:7 E
+//!    ...
+//!    9  Jump       -> 1     !R!             3:18 K!R!
+//! ```
+//!
+//! The notes are the builtin called by a `Func`, shown on the `PushConst` of the function number, and the labels a
+//! jump goes to. Labels are listed on their own line before the instruction they point at.
+
+use crate::builtins;
+use crate::byte_code::{ByteCode, OpCode};
+use crate::linker::{LabelEntry, UNRESOLVED_ADDRESS};
+use crate::span::Span;
+
+/// Longest source text shown for an instruction. Folded constants can cover a lot of source.
+const MAX_SOURCE_CHARS: usize = 40;
+
+/// List linked `code`. `labels` is the label table from the linker. With `source`, the text of each instruction's span
+/// is shown after its location. Labels with no name are shown by number.
+pub fn disassemble(code: &[ByteCode], labels: &[LabelEntry], source: Option<&str>) -> String {
+    let mut listing = String::new();
+
+    // Labels can point one past the last instruction, so the listing goes to the end of the code.
+    for address in 0..=code.len() {
+        for label in labels.iter().filter(|l| l.address == address) {
+            listing += &label_name(label);
+            listing.push('\n');
+        }
+
+        let op = match code.get(address) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let arg = match op.op_code {
+            OpCode::Jump => format!("-> {}", op.arg),
+            _ => op.arg.to_string(),
+        };
+
+        let note = match op.op_code {
+            OpCode::PushConst if is_func(code.get(address + 1)) => builtins::builtin_name(op.arg)
+                .map(String::from)
+                .unwrap_or_else(|| format!("function {}", op.arg)),
+            OpCode::Jump if op.arg == UNRESOLVED_ADDRESS => String::from("undefined label"),
+            OpCode::Jump => labels
+                .iter()
+                .filter(|l| l.address as i32 == op.arg)
+                .map(label_name)
+                .collect::<Vec<String>>()
+                .join(", "),
+            _ => String::new(),
+        };
+
+        let location = match op.span {
+            Some(span) => match source {
+                Some(source) => format!("{} {}", span, source_text(span, source)),
+                None => span.to_string(),
+            },
+            None => String::new(),
+        };
+
+        let op_code = format!("{:?}", op.op_code);
+        let line = format!(
+            "{:>4}  {:<10} {:<8} {:<15} {}",
+            address, op_code, arg, note, location
+        );
+        listing += line.trim_end();
+        listing.push('\n');
+    }
+
+    listing
+}
+
+fn is_func(op: Option<&ByteCode>) -> bool {
+    op.is_some_and(|op| op.op_code == OpCode::Func)
+}
+
+fn label_name(label: &LabelEntry) -> String {
+    match &label.name {
+        Some(name) => name.clone(),
+        None => format!("label {}", label.label_num),
+    }
+}
+
+/// The source text of `span` on one line, with comments removed and shortened if it is long.
+fn source_text(span: Span, source: &str) -> String {
+    let text = source.get(span.start..span.end).unwrap_or("");
+    let words: Vec<&str> = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| line.split_whitespace())
+        .collect();
+    let text = words.join(" ");
+
+    if text.chars().count() > MAX_SOURCE_CHARS {
+        let short: String = text.chars().take(MAX_SOURCE_CHARS - 3).collect();
+        format!("{}...", short)
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+
+    #[test]
+    fn test_disassemble() {
+        let source = "SRRR # counter\n!R! SRE\nSR SRRRE SRK!E! SK!R! !E!";
+        let program = compile(source).unwrap();
+
+        let expected = "   0  PushConst  3                        1:1 SRRR
+!R!
+   1  PushConst  1        output          2:5 SR
+   2  Func       0                        2:7 E
+   3  PushConst  1                        3:1 SR
+   4  PushConst  3        subtract        3:4 SRRR
+   5  Func       0                        3:8 E
+   6  PushConst  1                        3:10 SR
+   7  Jump       -> 10    !E!             3:12 K!E!
+   8  Push0      0                        3:17 S
+   9  Jump       -> 1     !R!             3:18 K!R!
+!E!
+";
+        assert_eq!(
+            expected,
+            disassemble(program.byte_code(), program.labels(), Some(source))
+        );
+    }
+
+    #[test]
+    fn test_disassemble_without_source() {
+        let code = vec![
+            ByteCode::new(OpCode::PushConst, 100),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::Jump, UNRESOLVED_ADDRESS),
+        ];
+
+        let expected = "   0  PushConst  100      function 100
+   1  Func       0
+   2  Jump       -> -1    undefined label
+";
+        assert_eq!(expected, disassemble(&code, &[], None));
+    }
+
+    #[test]
+    fn test_source_text() {
+        let source = "SRR # two\n  SRE";
+        assert_eq!("SRR SRE", source_text(Span::new(0, 15, 1, 1), source));
+
+        let long = "S".repeat(50);
+        assert_eq!(40, source_text(Span::new(0, 50, 1, 1), &long).len());
+    }
+}
@@ -0,0 +1,332 @@
+//! SHREK interpreter library.
+//!
+//! This crate exposes the tokenizer, parser, optimizer and virtual machine used by the `shrek` binary so that
+//! SHREK scripts can be embedded in other Rust programs. Most users only need [`compile`] and [`Program`]:
+//!
+//! ```
+//! let program = shrek_lang_rust::compile("SRRR SRE").unwrap();
+//!
+//! let mut output = Vec::new();
+//! let exit_code = program.run_with(&b""[..], &mut output).unwrap();
+//!
+//! assert_eq!(3, exit_code);
+//! assert_eq!(b"3\n", &output[..]);
+//! ```
+//!
+//! The lower level stages ([`shrek_parser::Tokenizer`], [`shrek_parser::SyntaxTree`],
+//! [`shrek_parser::generate_byte_code`], [`optimizer::optimize`], [`linker::link`] and [`shrek_vm::ShrekVM`]) are
+//! public for callers that need more control over compilation or execution. [`compiled_file`] saves a [`Program`] so
+//! it can be loaded later without compiling it again.
+
+pub mod arithmetic;
+pub mod builtins;
+pub mod byte_code;
+pub mod cell;
+pub mod compiled_file;
+pub mod disasm;
+pub mod limits;
+pub mod linker;
+pub mod optimizer;
+pub mod plugin;
+pub mod shrek_parser;
+pub mod shrek_vm;
+pub mod span;
+
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use arithmetic::ArithmeticMode;
+use byte_code::ByteCode;
+use cell::{CellType, CellValue};
+use limits::Limits;
+use linker::{LabelEntry, Linker};
+use shrek_parser::{check_labels, CodeGenerator, ParseResult, SyntaxError, SyntaxTree, Tokenizer};
+use shrek_vm::{ShrekVM, ShrekVMBuilder, VmResult};
+
+use num_bigint::BigInt;
+
+/// A compiled and optimized SHREK program, ready to be executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    byte_code: Vec<ByteCode>,
+    labels: Vec<LabelEntry>,
+    warnings: Vec<SyntaxError>,
+    arithmetic_mode: ArithmeticMode,
+    cell_type: CellType,
+}
+
+/// Options controlling how source code is compiled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompileOptions {
+    /// Report jumps to undefined labels and duplicate labels as warnings instead of errors.
+    pub label_warnings: bool,
+    /// How arithmetic overflow is handled, both when folding constants and when the program runs.
+    pub arithmetic_mode: ArithmeticMode,
+    /// The type of the values on the stack, both when folding constants and when the program runs.
+    pub cell_type: CellType,
+}
+
+/// Compile SHREK source code into an optimized [`Program`] using the default options.
+pub fn compile(source: &str) -> ParseResult<Program> {
+    compile_with(source, &CompileOptions::default())
+}
+
+/// Compile SHREK source code into an optimized [`Program`].
+pub fn compile_with(source: &str, options: &CompileOptions) -> ParseResult<Program> {
+    let tokenizer = Tokenizer::new();
+    let tokens = tokenizer.tokenize(source)?;
+    let syntax_tree = SyntaxTree::generate(&tokens)?;
+
+    let label_errors = check_labels(&syntax_tree);
+    let warnings = if options.label_warnings {
+        label_errors
+    } else {
+        if let Some(err) = label_errors.into_iter().next() {
+            return Err(err);
+        }
+        Vec::new()
+    };
+
+    let mut generator = CodeGenerator::new();
+    let byte_code = generator.generate(&syntax_tree)?;
+
+    let mode = options.arithmetic_mode;
+    let optimized = match options.cell_type {
+        CellType::I32 => optimizer::optimize_with::<i32>(&byte_code, mode),
+        CellType::I64 => optimizer::optimize_with::<i64>(&byte_code, mode),
+        CellType::BigInt => optimizer::optimize_with::<BigInt>(&byte_code, mode),
+    };
+
+    let mut program = Program::from_byte_code(optimized);
+    for label in program.labels.iter_mut() {
+        label.name = generator.label_name(label.label_num).map(String::from);
+    }
+    program.warnings = warnings;
+    program.arithmetic_mode = options.arithmetic_mode;
+    program.cell_type = options.cell_type;
+
+    Ok(program)
+}
+
+impl Program {
+    /// Create a program from generated byte code. The byte code is linked, but not optimized. The program uses the
+    /// default arithmetic mode and cell type.
+    pub fn from_byte_code(byte_code: Vec<ByteCode>) -> Program {
+        let mut linker = Linker::new();
+        let byte_code = linker.link(&byte_code);
+
+        Program {
+            byte_code,
+            labels: linker.labels().to_vec(),
+            warnings: Vec::new(),
+            arithmetic_mode: ArithmeticMode::default(),
+            cell_type: CellType::default(),
+        }
+    }
+
+    /// The linked byte code that will be executed by this program.
+    pub fn byte_code(&self) -> &[ByteCode] {
+        &self.byte_code
+    }
+
+    /// The labels removed from the code when it was linked.
+    pub fn labels(&self) -> &[LabelEntry] {
+        &self.labels
+    }
+
+    /// Problems found while compiling that did not stop compilation.
+    pub fn warnings(&self) -> &[SyntaxError] {
+        &self.warnings
+    }
+
+    /// The arithmetic mode the program was compiled for.
+    pub fn arithmetic_mode(&self) -> ArithmeticMode {
+        self.arithmetic_mode
+    }
+
+    /// The type of stack values the program was compiled for.
+    pub fn cell_type(&self) -> CellType {
+        self.cell_type
+    }
+
+    /// Run the program with stdin and stdout. Returns the program's exit code. Input is prompted for only when stdin is
+    /// a terminal, like the `shrek` command.
+    pub fn run(&self) -> VmResult<i32> {
+        let code = self.byte_code.clone();
+        let prompt = io::stdin().is_terminal();
+        match self.cell_type {
+            CellType::I32 => self.run_vm(ShrekVM::<i32>::builder(code), Limits::default(), prompt),
+            CellType::I64 => self.run_vm(ShrekVM::<i64>::builder(code), Limits::default(), prompt),
+            CellType::BigInt => {
+                self.run_vm(ShrekVM::<BigInt>::builder(code), Limits::default(), prompt)
+            }
+        }
+    }
+
+    /// Run the program reading from `input` and writing to `output` instead of stdin and stdout. Returns the
+    /// program's exit code. There is no input prompt, so `output` only holds what the program wrote.
+    pub fn run_with<R, W>(&self, input: R, output: W) -> VmResult<i32>
+    where
+        R: BufRead,
+        W: Write,
+    {
+        self.run_with_limits(input, output, Limits::default())
+    }
+
+    /// Run the program like `run_with`, stopping it with an error if it exceeds any of `limits`.
+    pub fn run_with_limits<R, W>(&self, input: R, output: W, limits: Limits) -> VmResult<i32>
+    where
+        R: BufRead,
+        W: Write,
+    {
+        let code = self.byte_code.clone();
+        match self.cell_type {
+            CellType::I32 => self.run_vm(
+                ShrekVM::<i32>::builder(code).input(input).output(output),
+                limits,
+                false,
+            ),
+            CellType::I64 => self.run_vm(
+                ShrekVM::<i64>::builder(code).input(input).output(output),
+                limits,
+                false,
+            ),
+            CellType::BigInt => self.run_vm(
+                ShrekVM::<BigInt>::builder(code).input(input).output(output),
+                limits,
+                false,
+            ),
+        }
+    }
+
+    fn run_vm<C: CellValue>(
+        &self,
+        builder: ShrekVMBuilder<C>,
+        limits: Limits,
+        prompt: bool,
+    ) -> VmResult<i32> {
+        let mut vm = builder
+            .arithmetic_mode(self.arithmetic_mode)
+            .prompt(prompt)
+            .limits(limits)
+            .build();
+        vm.run().map(|exit_code| exit_code.to_i32_saturating())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_links_labels() {
+        let program = compile("SRR !S! SK!E! !E!").unwrap();
+
+        assert!(program
+            .byte_code()
+            .iter()
+            .all(|code| code.op_code != byte_code::OpCode::Label));
+
+        let labels: Vec<(Option<&str>, usize)> = program
+            .labels()
+            .iter()
+            .map(|l| (l.name.as_deref(), l.address))
+            .collect();
+        assert_eq!(vec![(Some("!S!"), 1), (Some("!E!"), 3)], labels);
+    }
+
+    #[test]
+    fn test_compile_undefined_label() {
+        let err = compile("SK!E!").unwrap_err();
+        assert_eq!("jump to undefined label !E!", err.message);
+    }
+
+    #[test]
+    fn test_compile_label_warnings() {
+        let options = CompileOptions {
+            label_warnings: true,
+            ..CompileOptions::default()
+        };
+        let program = compile_with("!S! SK!E! !S!", &options).unwrap();
+
+        let messages: Vec<&str> = program
+            .warnings()
+            .iter()
+            .map(|w| w.message.as_str())
+            .collect();
+        assert_eq!(
+            vec![
+                "jump to undefined label !E!",
+                "label !S! is defined more than once"
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_compile_arithmetic_mode() {
+        // Squaring 16 three times overflows. The last square is folded in wrapping mode and left for the VM in
+        // checked mode.
+        let source = "SRRRRRRRRRRRRRRRR SRRRRRRRRRE SRRRRRRRRRE SRRRRRRRRRE SRE";
+
+        let err = compile(source).unwrap().run_with(&b""[..], Vec::new());
+        assert_eq!("integer overflow in square", err.unwrap_err().message);
+
+        let options = CompileOptions {
+            arithmetic_mode: ArithmeticMode::Wrapping,
+            ..CompileOptions::default()
+        };
+        let program = compile_with(source, &options).unwrap();
+        assert_eq!(3, program.byte_code().len());
+
+        let mut output = Vec::new();
+        program.run_with(&b""[..], &mut output).unwrap();
+        assert_eq!(b"0\n", &output[..]);
+    }
+
+    #[test]
+    fn test_compile_cell_type() {
+        // Square 256 twice. The result does not fit in an i32.
+        let source = "SRRRRRRRRRRRRRRRR SRRRRRRRRRE SRRRRRRRRRE SRRRRRRRRRE SRE";
+
+        let options = CompileOptions {
+            cell_type: CellType::I64,
+            ..CompileOptions::default()
+        };
+        let mut output = Vec::new();
+        compile_with(source, &options)
+            .unwrap()
+            .run_with(&b""[..], &mut output)
+            .unwrap();
+        assert_eq!(b"4294967296\n", &output[..]);
+
+        let options = CompileOptions {
+            cell_type: CellType::BigInt,
+            ..CompileOptions::default()
+        };
+        let source =
+            "SRRRRRRRRRRRRRRRR SRRRRRRRRRE SRRRRRRRRRE SRRRRRRRRRE SRRRRRRRRRE SRRRRRRRRRE SRE";
+        let mut output = Vec::new();
+        compile_with(source, &options)
+            .unwrap()
+            .run_with(&b""[..], &mut output)
+            .unwrap();
+        assert_eq!(b"340282366920938463463374607431768211456\n", &output[..]);
+    }
+
+    #[test]
+    fn test_run_with_limits() {
+        let program = compile("!S! SR SK!S!").unwrap();
+        let limits = Limits {
+            max_instructions: Some(1000),
+            ..Limits::default()
+        };
+
+        let err = program
+            .run_with_limits(&b""[..], Vec::new(), limits)
+            .unwrap_err();
+        assert_eq!(
+            shrek_vm::ErrorKind::LimitExceeded(limits::Limit::Instructions),
+            err.kind
+        );
+    }
+}
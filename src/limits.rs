@@ -0,0 +1,122 @@
+//! Limits on the resources a program can use, for running scripts that are not trusted.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Instant;
+
+/// Most bytes of memory cells a VM can have, whether or not `max_memory` is set. Growing past it is a memory limit
+/// error, so a program cannot ask the host for more than it can give.
+pub const MAX_MEMORY_BYTES: usize = 1 << 30;
+
+/// Most bits a single cell can use. Only a type with no fixed width can reach it, for example by squaring a big
+/// integer over and over. A bigger result is a cell size limit error.
+pub const MAX_CELL_BITS: u64 = 1 << 16;
+
+/// Resource limits for a `ShrekVM`. Every limit is off by default. Counts are kept from when the VM is created or
+/// last reset, so code appended to a VM shares the same budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    /// Maximum number of instructions to execute.
+    pub max_instructions: Option<u64>,
+    /// Maximum number of items on the stack.
+    pub max_stack_depth: Option<usize>,
+    /// Maximum number of calls that have not returned yet.
+    pub max_call_depth: Option<usize>,
+    /// Maximum number of cells of memory.
+    pub max_memory: Option<usize>,
+    /// Maximum number of bytes written to the output stream.
+    pub max_output_bytes: Option<u64>,
+    /// Time after which the program is stopped. The deadline is checked between instructions, so a builtin waiting
+    /// for input is not interrupted.
+    pub deadline: Option<Instant>,
+}
+
+/// A limit that was exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Instructions,
+    StackDepth,
+    CallDepth,
+    Memory,
+    CellSize,
+    OutputBytes,
+    Deadline,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Instructions => write!(f, "instruction limit exceeded"),
+            Limit::StackDepth => write!(f, "stack depth limit exceeded"),
+            Limit::CallDepth => write!(f, "call depth limit exceeded"),
+            Limit::Memory => write!(f, "memory limit exceeded"),
+            Limit::CellSize => write!(f, "cell size limit exceeded"),
+            Limit::OutputBytes => write!(f, "output limit exceeded"),
+            Limit::Deadline => write!(f, "deadline exceeded"),
+        }
+    }
+}
+
+/// Wraps the VM's output stream and stops writing at the output limit.
+pub(crate) struct LimitedWriter<'io> {
+    inner: Box<dyn Write + 'io>,
+    pub(crate) written: u64,
+    pub(crate) max: Option<u64>,
+    pub(crate) exceeded: bool,
+}
+
+impl<'io> LimitedWriter<'io> {
+    pub(crate) fn new(inner: Box<dyn Write + 'io>) -> LimitedWriter<'io> {
+        LimitedWriter {
+            inner,
+            written: 0,
+            max: None,
+            exceeded: false,
+        }
+    }
+}
+
+impl<'io> Write for LimitedWriter<'io> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buf = buf;
+        if let Some(max) = self.max {
+            // Write as much as fits. The next write, with no room left, fails.
+            let remaining = max.saturating_sub(self.written);
+            if remaining == 0 && !buf.is_empty() {
+                self.exceeded = true;
+                return Err(io::Error::other(Limit::OutputBytes.to_string()));
+            }
+            if (buf.len() as u64) > remaining {
+                buf = &buf[..remaining as usize];
+            }
+        }
+
+        let count = self.inner.write(buf)?;
+        self.written += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limited_writer() {
+        let mut output = Vec::new();
+        let mut writer = LimitedWriter::new(Box::new(&mut output));
+        writer.max = Some(4);
+
+        writer.write_all(b"abc").unwrap();
+        assert!(writer.write_all(b"de").is_err());
+        assert!(writer.exceeded);
+        assert_eq!(4, writer.written);
+
+        drop(writer);
+        assert_eq!(b"abcd", &output[..]);
+    }
+}
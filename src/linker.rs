@@ -0,0 +1,154 @@
+//! Resolves jumps to instruction addresses.
+//!
+//! Generated byte code marks jump targets with `Label` operations, and jumps use the label's number as their argument.
+//! Linking removes the labels from the code and rewrites each jump's argument to the address of the instruction that
+//! followed its label, so the VM can jump without looking anything up. The removed labels are kept in a separate table
+//! for debugging.
+
+use crate::byte_code::{ByteCode, OpCode};
+use crate::span::Span;
+
+use std::collections::HashMap;
+
+/// Jump argument used when the jump's label is not defined. Taking the jump is a runtime error.
+pub const UNRESOLVED_ADDRESS: i32 = -1;
+
+/// A label removed from the code while linking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelEntry {
+    pub label_num: i32,
+    /// The label's text, including the `!` characters, if known.
+    pub name: Option<String>,
+    /// Address of the instruction that followed the label.
+    pub address: usize,
+    pub span: Option<Span>,
+}
+
+/// Links code a piece at a time. Each piece is placed after all previously linked pieces, and jumps can target labels
+/// in the same piece or any earlier one.
+#[derive(Default)]
+pub struct Linker {
+    addresses: HashMap<i32, usize>,
+    labels: Vec<LabelEntry>,
+    next_address: usize,
+}
+
+/// Link a complete program. Returns the linked code and the label table.
+pub fn link(code: &[ByteCode]) -> (Vec<ByteCode>, Vec<LabelEntry>) {
+    let mut linker = Linker::new();
+    let linked = linker.link(code);
+    (linked, linker.labels)
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker {
+            addresses: HashMap::new(),
+            labels: Vec::new(),
+            next_address: 0,
+        }
+    }
+
+    pub fn link(&mut self, code: &[ByteCode]) -> Vec<ByteCode> {
+        // Find the address of every label first so jumps forward in the code can be resolved. If a label is defined
+        // more than once, the last definition is used.
+        let mut address = self.next_address;
+        for op in code.iter() {
+            if op.op_code == OpCode::Label {
+                self.addresses.insert(op.arg, address);
+                self.labels.push(LabelEntry {
+                    label_num: op.arg,
+                    name: None,
+                    address,
+                    span: op.span,
+                });
+            } else {
+                address += 1;
+            }
+        }
+
+        let mut linked = Vec::with_capacity(address - self.next_address);
+        for op in code.iter() {
+            match op.op_code {
+                OpCode::Label => (),
+                OpCode::Jump => {
+                    let target = match self.addresses.get(&op.arg) {
+                        Some(x) => *x as i32,
+                        None => UNRESOLVED_ADDRESS,
+                    };
+                    linked.push(ByteCode { arg: target, ..*op });
+                }
+                _ => linked.push(*op),
+            }
+        }
+
+        self.next_address = address;
+        linked
+    }
+
+    /// All labels linked so far, in the order they were defined.
+    pub fn labels(&self) -> &[LabelEntry] {
+        &self.labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link() {
+        let code = vec![
+            ByteCode::new(OpCode::Label, 0),
+            ByteCode::new(OpCode::PushConst, 0),
+            ByteCode::new(OpCode::Jump, 1),
+            ByteCode::new(OpCode::Jump, 0),
+            ByteCode::new(OpCode::Label, 1),
+        ];
+
+        let (linked, labels) = link(&code);
+
+        assert_eq!(
+            vec![
+                ByteCode::new(OpCode::PushConst, 0),
+                ByteCode::new(OpCode::Jump, 3),
+                ByteCode::new(OpCode::Jump, 0),
+            ],
+            linked
+        );
+        assert_eq!(2, labels.len());
+        assert_eq!((0, 0), (labels[0].label_num, labels[0].address));
+        assert_eq!((1, 3), (labels[1].label_num, labels[1].address));
+    }
+
+    #[test]
+    fn test_link_undefined_label() {
+        let (linked, _) = link(&[ByteCode::new(OpCode::Jump, 4)]);
+        assert_eq!(UNRESOLVED_ADDRESS, linked[0].arg);
+    }
+
+    #[test]
+    fn test_link_pieces() {
+        let mut linker = Linker::new();
+
+        let first = linker.link(&[
+            ByteCode::new(OpCode::PushConst, 1),
+            ByteCode::new(OpCode::Label, 0),
+            ByteCode::new(OpCode::Bump, 0),
+        ]);
+        assert_eq!(2, first.len());
+
+        let second = linker.link(&[
+            ByteCode::new(OpCode::Jump, 0),
+            ByteCode::new(OpCode::Jump, 1),
+            ByteCode::new(OpCode::Label, 1),
+        ]);
+        assert_eq!(
+            vec![
+                ByteCode::new(OpCode::Jump, 1),
+                ByteCode::new(OpCode::Jump, 4)
+            ],
+            second
+        );
+    }
+}
@@ -1,56 +1,342 @@
-mod builtins;
-mod byte_code;
-mod optimizer;
-mod shrek_parser;
-mod shrek_vm;
+mod repl;
 
 use std::env;
 use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use byte_code::ByteCode;
-use shrek_parser::*;
-use shrek_vm::ShrekVM;
+use shrek_lang_rust::cell::{CellType, CellValue};
+use shrek_lang_rust::disasm::disassemble;
+use shrek_lang_rust::limits::Limits;
+use shrek_lang_rust::plugin::load_plugin;
+use shrek_lang_rust::shrek_vm::{ErrorKind, ShrekVM, VmResult};
+use shrek_lang_rust::{compile_with, compiled_file, CompileOptions, Program};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Invalid arguments. Expected source file.");
-        std::process::exit(1);
+use num_bigint::BigInt;
+
+use repl::Repl;
+
+const USAGE: &str = "\
+Usage: shrek [options] [repl | <source file> | <compiled file>]
+       shrek [options] compile <source file> [-o <compiled file>]
+       shrek [options] disasm <source file | compiled file>
+
+Options:
+  -o <path>                Where to write the compiled file. Defaults to the source path with a .shrekc extension.
+  --strip                  Leave source spans out of the compiled file.
+  --plugin <path>          Load a native plugin. Can be given more than once.
+  --warn-labels            Report label problems as warnings instead of errors.
+  --arithmetic <mode>      Overflow handling: checked (default), wrapping or saturating.
+  --cell <type>            Stack value type: i32 (default), i64 or bigint.
+  --prompt                 Write \"input: \" before reading input.
+  --no-prompt              Read input without a prompt. This is the default when stdin is not a terminal.
+  --max-instructions <n>   Stop after executing n instructions.
+  --max-stack <n>          Stop when the stack holds more than n items.
+  --max-call-depth <n>     Stop when more than n calls have not returned.
+  --max-memory <n>         Stop when memory is grown past n cells.
+  --max-output <bytes>     Stop after writing this many bytes of output.
+  --timeout <ms>           Stop after this many milliseconds.
+  --distinct-exit-codes    Exit with 2 when the program's exit value is one the interpreter uses for errors.
+
+Exit codes: the program's exit value, or 1, 3 and 10-21 for errors (see README). With --distinct-exit-codes, these
+codes only come from the interpreter.";
+
+enum Command {
+    Run(String),
+    Compile(String),
+    Disasm(String),
+    Repl,
+}
+
+struct Options {
+    command: Command,
+    plugins: Vec<String>,
+    compile_options: CompileOptions,
+    /// Path of the compiled file to write.
+    output: Option<String>,
+    strip: bool,
+    limits: Limits,
+    /// Keep program exit values out of the codes used for errors.
+    distinct_exit_codes: bool,
+    /// Whether to prompt for input. Not set means prompt only when stdin is a terminal.
+    prompt: Option<bool>,
+}
+
+impl Options {
+    fn prompt(&self) -> bool {
+        self.prompt.unwrap_or_else(|| io::stdin().is_terminal())
     }
+}
 
-    let input_code = match fs::read_to_string(&args[1]) {
-        Ok(text) => text,
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Some(x) => x,
+        None => {
+            eprintln!("Invalid arguments.\n{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let source_path = match &options.command {
+        Command::Run(path) => path.clone(),
+        Command::Compile(path) => {
+            compile_file(path, &options);
+            return;
+        }
+        Command::Disasm(path) => {
+            let (program, input_code) = load_program(path, &options);
+            // Compiled files have no source text, so only the line and column of each instruction are shown.
+            let source = Some(input_code.as_str()).filter(|s| !s.is_empty());
+            print!(
+                "{}",
+                disassemble(program.byte_code(), program.labels(), source)
+            );
+            return;
+        }
+        Command::Repl => {
+            match options.compile_options.cell_type {
+                CellType::I32 => run_repl::<i32>(&options),
+                CellType::I64 => run_repl::<i64>(&options),
+                CellType::BigInt => run_repl::<BigInt>(&options),
+            }
+            return;
+        }
+    };
+
+    let (program, input_code) = load_program(&source_path, &options);
+
+    let result = match program.cell_type() {
+        CellType::I32 => run_program::<i32>(&program, &options),
+        CellType::I64 => run_program::<i64>(&program, &options),
+        CellType::BigInt => run_program::<BigInt>(&program, &options),
+    };
+
+    let exit_code = match result {
+        Ok(x) if options.distinct_exit_codes => program_exit_code(x),
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("{}", err.render(&source_path, &input_code));
+            error_exit_code(err.kind)
+        }
+    };
+
+    std::process::exit(exit_code);
+}
+
+/// Load a compiled file, or compile a source file. Returns the program and the source text, which is empty for a
+/// compiled file. Exits if the file cannot be loaded or compiled.
+fn load_program(path: &str, options: &Options) -> (Program, String) {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
         Err(err) => {
             eprintln!("Error reading source file: {:?}", err);
             std::process::exit(1);
         }
     };
 
-    let byte_code = match parse_code(&input_code) {
-        Ok(c) => c,
+    // Compiled files carry their own cell type and arithmetic mode. There is no source text to show in errors.
+    if compiled_file::is_compiled(&bytes) {
+        match compiled_file::read(&bytes) {
+            Ok(p) => (p, String::new()),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let input_code = source_text(bytes);
+        (compile_source(path, &input_code, options), input_code)
+    }
+}
+
+/// Compile the source file at `source_path` and write it as a compiled file.
+fn compile_file(source_path: &str, options: &Options) {
+    let input_code = match fs::read(source_path) {
+        Ok(bytes) => source_text(bytes),
         Err(err) => {
-            eprintln!("Parse error: {:?}", err);
+            eprintln!("Error reading source file: {:?}", err);
             std::process::exit(1);
         }
     };
 
-    let mut vm = ShrekVM::new(byte_code);
-    let exit_code = match vm.run() {
-        Ok(x) => x,
+    let program = compile_source(source_path, &input_code, options);
+    let output_path = match &options.output {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(source_path).with_extension(compiled_file::EXTENSION),
+    };
+
+    if let Err(err) = fs::write(&output_path, compiled_file::write(&program, !options.strip)) {
+        eprintln!("Error writing compiled file: {:?}", err);
+        std::process::exit(1);
+    }
+}
+
+fn source_text(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(text) => text,
         Err(err) => {
-            eprintln!("Shrek RuntimeError: {:?}", err);
-            3
+            eprintln!("Error reading source file: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Compile source code, printing any warnings. Exits on a syntax error.
+fn compile_source(source_path: &str, input_code: &str, options: &Options) -> Program {
+    let program = match compile_with(input_code, &options.compile_options) {
+        Ok(p) => p,
+        Err(err) => {
+            eprintln!("{}", err.render(source_path, input_code));
+            std::process::exit(1);
         }
     };
 
-    std::process::exit(exit_code);
+    for warning in program.warnings() {
+        eprintln!("{}", warning.render_warning(source_path, input_code));
+    }
+
+    program
 }
 
-fn parse_code(input_code: &str) -> ParseResult<Vec<ByteCode>> {
-    let tokenizer = Tokenizer::new();
-    let tokens = tokenizer.tokenize(&input_code)?;
-    let syntax_tree = SyntaxTree::generate(&tokens)?;
-    let byte_code = generate_byte_code(&syntax_tree)?;
+/// Exit code for a program stopped by a runtime error, so scripts running the interpreter can tell kinds of errors
+/// apart. A program can exit with the same values itself unless `--distinct-exit-codes` is given.
+fn error_exit_code(kind: ErrorKind) -> i32 {
+    match kind {
+        ErrorKind::Host | ErrorKind::FunctionNumberInUse(_) => 3,
+        ErrorKind::StackUnderflow { .. } => 10,
+        ErrorKind::InvalidJumpType(_) => 11,
+        ErrorKind::UnknownFunction(_) => 12,
+        ErrorKind::LabelNotFound => 13,
+        ErrorKind::DivisionByZero => 14,
+        ErrorKind::Overflow => 15,
+        ErrorKind::LimitExceeded(_) => 16,
+        ErrorKind::Io => 17,
+        ErrorKind::InvalidInput => 18,
+        ErrorKind::InvalidArgument => 19,
+        ErrorKind::ReturnWithoutCall => 20,
+        ErrorKind::MemoryOutOfBounds { .. } => 21,
+    }
+}
+
+/// Exit code for a program that finished with `exit_value`, with `--distinct-exit-codes`. The operating system only
+/// keeps the low 8 bits, so values are reserved by those. Reserved values become 2, which no error uses.
+fn program_exit_code(exit_value: i32) -> i32 {
+    match exit_value & 0xff {
+        1 | 2 | 3 | 10..=21 => 2,
+        _ => exit_value,
+    }
+}
+
+fn run_repl<C: CellValue>(options: &Options) {
+    let mut vm = ShrekVM::<C>::builder(Vec::new())
+        .arithmetic_mode(options.compile_options.arithmetic_mode)
+        .prompt(options.prompt())
+        .limits(options.limits)
+        .build();
+    load_plugins(&mut vm, &options.plugins);
+
+    if let Err(err) = Repl::new(vm).run() {
+        eprintln!("I/O error: {:?}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run_program<C: CellValue>(program: &Program, options: &Options) -> VmResult<i32> {
+    let mut vm = ShrekVM::<C>::builder(program.byte_code().to_vec())
+        .arithmetic_mode(program.arithmetic_mode())
+        .prompt(options.prompt())
+        .limits(options.limits)
+        .build();
+    load_plugins(&mut vm, &options.plugins);
+
+    vm.run().map(|exit_code| exit_code.to_i32_saturating())
+}
+
+fn load_plugins<C: CellValue>(vm: &mut ShrekVM<C>, plugins: &[String]) {
+    for plugin in plugins.iter() {
+        // Plugins are trusted native code, given explicitly on the command line.
+        if let Err(err) = unsafe { load_plugin(vm, plugin) } {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Option<Options> {
+    let mut command = None;
+    let mut plugins = Vec::new();
+    let mut compile_options = CompileOptions::default();
+    let mut limits = Limits::default();
+    let mut prompt = None;
+    let mut output = None;
+    let mut strip = false;
+    let mut distinct_exit_codes = false;
+    let mut subcommand = None;
+
+    while let Some(arg) = args.next() {
+        if arg == "--plugin" {
+            plugins.push(args.next()?);
+        } else if arg == "--warn-labels" {
+            compile_options.label_warnings = true;
+        } else if arg == "--arithmetic" {
+            compile_options.arithmetic_mode = args.next()?.parse().ok()?;
+        } else if arg == "--cell" {
+            compile_options.cell_type = args.next()?.parse().ok()?;
+        } else if arg == "-o" {
+            output = Some(args.next()?);
+        } else if arg == "--strip" {
+            strip = true;
+        } else if arg == "--prompt" {
+            prompt = Some(true);
+        } else if arg == "--no-prompt" {
+            prompt = Some(false);
+        } else if arg == "--max-instructions" {
+            limits.max_instructions = Some(args.next()?.parse().ok()?);
+        } else if arg == "--max-stack" {
+            limits.max_stack_depth = Some(args.next()?.parse().ok()?);
+        } else if arg == "--max-call-depth" {
+            limits.max_call_depth = Some(args.next()?.parse().ok()?);
+        } else if arg == "--max-memory" {
+            limits.max_memory = Some(args.next()?.parse().ok()?);
+        } else if arg == "--max-output" {
+            limits.max_output_bytes = Some(args.next()?.parse().ok()?);
+        } else if arg == "--distinct-exit-codes" {
+            distinct_exit_codes = true;
+        } else if arg == "--timeout" {
+            // The timeout counts from when the interpreter starts.
+            let millis = args.next()?.parse().ok()?;
+            limits.deadline = Some(Instant::now() + Duration::from_millis(millis));
+        } else if (arg == "compile" || arg == "disasm") && command.is_none() && subcommand.is_none()
+        {
+            subcommand = Some(arg);
+        } else if command.is_none() {
+            command = match subcommand.as_deref() {
+                Some("compile") => Some(Command::Compile(arg)),
+                Some(_) => Some(Command::Disasm(arg)),
+                None if arg == "repl" => Some(Command::Repl),
+                None => Some(Command::Run(arg)),
+            };
+        } else {
+            return None;
+        }
+    }
+
+    // Compile and disasm need a file. The output options only apply when compiling.
+    let is_compiling = matches!(command, Some(Command::Compile(_)));
+    if (subcommand.is_some() && command.is_none()) || (!is_compiling && (output.is_some() || strip))
+    {
+        return None;
+    }
 
-    Ok(optimizer::optimize(&byte_code))
+    Some(Options {
+        command: command.unwrap_or(Command::Repl),
+        plugins,
+        compile_options,
+        output,
+        strip,
+        limits,
+        distinct_exit_codes,
+        prompt,
+    })
 }
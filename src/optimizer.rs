@@ -1,10 +1,56 @@
+use crate::arithmetic::ArithmeticMode;
 use crate::builtins;
 use crate::byte_code::*;
+use crate::cell::CellValue;
+use crate::span::Span;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::vec::Vec;
 
 const MAX_OPTIMIZE_LOOPS: i32 = 32;
 
-pub fn optimize(code: &Vec<ByteCode>) -> Vec<ByteCode> {
+/// A straight line of code with no jumps into or out of the middle. A block starts at a label (or the start of the
+/// program) and ends at a jump (or right before the next label).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub code: Vec<ByteCode>,
+    /// Indexes of the blocks that can run after this one.
+    pub successors: Vec<usize>,
+}
+
+/// The basic blocks of a program, in program order. The first block is the entry point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+}
+
+/// Optimize unlinked byte code for a VM using `i32` cells and the default arithmetic mode.
+pub fn optimize(code: &[ByteCode]) -> Vec<ByteCode> {
+    optimize_with::<i32>(code, ArithmeticMode::default())
+}
+
+/// Optimize unlinked byte code. Constants are folded within each basic block, so folding never crosses a label or a
+/// jump, and blocks that can never run are removed. Folded arithmetic is done with `C` values using `mode`, which
+/// must match the cell type and arithmetic mode of the VM that runs the code.
+pub fn optimize_with<C: CellValue>(code: &[ByteCode], mode: ArithmeticMode) -> Vec<ByteCode> {
+    let mut cfg = ControlFlowGraph::build(code);
+    for block in cfg.blocks.iter_mut() {
+        block.code = optimize_block::<C>(&block.code, mode);
+    }
+
+    // Folding can turn a jump's type into a constant, which makes more blocks unreachable.
+    let cfg = ControlFlowGraph::build(&cfg.into_code());
+    let reachable = cfg.reachable();
+
+    cfg.blocks
+        .into_iter()
+        .zip(reachable)
+        .filter(|(_, is_reachable)| *is_reachable)
+        .flat_map(|(block, _)| block.code)
+        .collect()
+}
+
+fn optimize_block<C: CellValue>(code: &[ByteCode], mode: ArithmeticMode) -> Vec<ByteCode> {
     // Must optimize easy constants before attempting to compress arithmetic.
     let mut result = optimize_easy_constants(code);
 
@@ -14,21 +60,25 @@ pub fn optimize(code: &Vec<ByteCode>) -> Vec<ByteCode> {
     loop {
         let mut is_optimizing = false;
 
-        match optimize_1_arg_arithmetic(&result) {
-            Some(optimized) => {
-                is_optimizing = true;
-                result = optimized;
-            }
-            None => (),
+        if let Some(optimized) = optimize_globals(&result) {
+            is_optimizing = true;
+            result = optimized;
         }
 
-        match optimize_2_arg_arithmetic(&result) {
-            Some(optimized) => {
-                is_optimizing = true;
-                result = optimized
-            }
-            None => (),
-        };
+        if let Some(optimized) = optimize_stack_ops(&result) {
+            is_optimizing = true;
+            result = optimized;
+        }
+
+        if let Some(optimized) = optimize_1_arg_arithmetic::<C>(&result, mode) {
+            is_optimizing = true;
+            result = optimized;
+        }
+
+        if let Some(optimized) = optimize_2_arg_arithmetic::<C>(&result, mode) {
+            is_optimizing = true;
+            result = optimized;
+        }
 
         // Loop exit when code was not optimized or the maximum number of optimizations has been reached.
         counter += 1;
@@ -40,9 +90,119 @@ pub fn optimize(code: &Vec<ByteCode>) -> Vec<ByteCode> {
     result
 }
 
+impl ControlFlowGraph {
+    /// Split unlinked byte code into basic blocks and connect them.
+    pub fn build(code: &[ByteCode]) -> ControlFlowGraph {
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        let mut current = Vec::new();
+
+        for op in code.iter() {
+            if op.op_code == OpCode::Label && !current.is_empty() {
+                blocks.push(BasicBlock::new(current));
+                current = Vec::new();
+            }
+
+            current.push(*op);
+
+            if op.op_code == OpCode::Jump {
+                blocks.push(BasicBlock::new(current));
+                current = Vec::new();
+            }
+        }
+
+        if !current.is_empty() {
+            blocks.push(BasicBlock::new(current));
+        }
+
+        // Map label numbers to the block they start. If a label is defined more than once the last one is used,
+        // which matches the linker.
+        let mut label_blocks = HashMap::new();
+        for (i, block) in blocks.iter().enumerate() {
+            if block.code[0].op_code == OpCode::Label {
+                label_blocks.insert(block.code[0].arg, i);
+            }
+        }
+
+        let block_count = blocks.len();
+        for (i, block) in blocks.iter_mut().enumerate() {
+            let last = block.code.len() - 1;
+            let falls_through = i + 1 < block_count;
+
+            if block.code[last].op_code == OpCode::Jump {
+                let jump_type = if last > 0 {
+                    constant_value(&block.code[last - 1])
+                } else {
+                    None
+                };
+
+                // A return (type 4) does not use its label. It continues after the call it returns to, and the
+                // block after a call is already a successor of the call's block.
+                if jump_type != Some(4) {
+                    if let Some(target) = label_blocks.get(&block.code[last].arg) {
+                        block.successors.push(*target);
+                    }
+                }
+
+                // A jump type of 0 always jumps and a return never continues in the next block, so the next block
+                // is not reached from this one. A call (type 3) continues in the next block when it returns.
+                let is_unconditional = matches!(jump_type, Some(0) | Some(4));
+                if falls_through && !is_unconditional {
+                    block.successors.push(i + 1);
+                }
+            } else if falls_through {
+                block.successors.push(i + 1);
+            }
+        }
+
+        ControlFlowGraph { blocks }
+    }
+
+    /// Find the blocks that can run, starting from the first block.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut pending = Vec::new();
+        if !self.blocks.is_empty() {
+            pending.push(0);
+        }
+
+        while let Some(i) = pending.pop() {
+            if reachable[i] {
+                continue;
+            }
+            reachable[i] = true;
+            pending.extend(self.blocks[i].successors.iter().copied());
+        }
+
+        reachable
+    }
+
+    /// Join the blocks back into a single list of code.
+    pub fn into_code(self) -> Vec<ByteCode> {
+        self.blocks.into_iter().flat_map(|b| b.code).collect()
+    }
+}
+
+impl BasicBlock {
+    fn new(code: Vec<ByteCode>) -> BasicBlock {
+        BasicBlock {
+            code,
+            successors: Vec::new(),
+        }
+    }
+}
+
+/// The value pushed by an instruction that pushes a constant.
+fn constant_value(byte_code: &ByteCode) -> Option<i32> {
+    match byte_code.op_code {
+        OpCode::Push0 => Some(0),
+        OpCode::PushConst => Some(byte_code.arg),
+        _ => None,
+    }
+}
+
 /// Optimize code that is a Push0 then a chain of bumps. This will compress the operation into a
 /// single push constant with the bumps combined into a single arg.
-fn optimize_easy_constants(code: &Vec<ByteCode>) -> Vec<ByteCode> {
+fn optimize_easy_constants(code: &[ByteCode]) -> Vec<ByteCode> {
     let mut result = Vec::new();
 
     let mut push_index: Option<usize> = None;
@@ -51,17 +211,7 @@ fn optimize_easy_constants(code: &Vec<ByteCode>) -> Vec<ByteCode> {
     for i in 0..code.len() {
         // Check if the bump chain of bumping has ended before handling current operation.
         if push_index.is_some() && code[i].op_code != OpCode::Bump {
-            // If there was a push and it was bumped, then it can be simplified into a single operation of
-            // pushing a constant to the stack. Replace the push0 and bumps with a single operation.
-            if bump_value > 0 {
-                result.push(ByteCode {
-                    op_code: OpCode::PushConst,
-                    arg: bump_value,
-                });
-            } else {
-                // There were no bumps, so the push0 needs to be copied to the output code.
-                result.push(code[push_index.unwrap()].clone());
-            }
+            push_bumped_constant(&mut result, &code[push_index.unwrap()..i], bump_value);
 
             push_index = None;
             bump_value = 0;
@@ -73,23 +223,39 @@ fn optimize_easy_constants(code: &Vec<ByteCode>) -> Vec<ByteCode> {
         } else if push_index.is_some() && code[i].op_code == OpCode::Bump {
             bump_value += 1;
         } else {
-            result.push(code[i].clone());
+            result.push(code[i]);
         }
     }
 
-    // A non None value in the push index indicates the last operation was a push, which
+    // A non None value in the push index indicates the code ended with a push chain, which
     // needs to be added.
-    if push_index.is_some() {
-        result.push(code.last().unwrap().clone());
+    if let Some(index) = push_index {
+        push_bumped_constant(&mut result, &code[index..], bump_value);
     }
 
     result
 }
 
+/// Add the replacement for a chain of a push0 followed by `bump_value` bumps.
+fn push_bumped_constant(result: &mut Vec<ByteCode>, chain: &[ByteCode], bump_value: i32) {
+    // If there was a push and it was bumped, then it can be simplified into a single operation of
+    // pushing a constant to the stack. Replace the push0 and bumps with a single operation.
+    if bump_value > 0 {
+        result.push(ByteCode {
+            op_code: OpCode::PushConst,
+            arg: bump_value,
+            span: merge_spans(&chain[0], &chain[chain.len() - 1]),
+        });
+    } else {
+        // There were no bumps, so the push0 needs to be copied to the output code.
+        result.push(chain[0]);
+    }
+}
+
 /// Optimize code like the following to a single constant. This assumes that "easy constant" optimization has been
 /// executed.
 ///
-/// ```
+/// ```text
 /// Push Constant <= v1 (stack top - 1)
 /// Push Constant <= v0 (stack top when func executing)
 /// Push Constant <= If this constant is an arithmetic function.
@@ -98,7 +264,10 @@ fn optimize_easy_constants(code: &Vec<ByteCode>) -> Vec<ByteCode> {
 ///
 // This series of commands can be turned into a single constant because arithmetic on constants will always be
 /// a constant value. This will cover cases where two constants are "mathed" into a single constant.
-fn optimize_2_arg_arithmetic(code: &Vec<ByteCode>) -> Option<Vec<ByteCode>> {
+fn optimize_2_arg_arithmetic<C: CellValue>(
+    code: &[ByteCode],
+    mode: ArithmeticMode,
+) -> Option<Vec<ByteCode>> {
     // If there are not enough operations in the code, do not attempt to optimize.
     if code.len() < 4 {
         return None;
@@ -111,7 +280,7 @@ fn optimize_2_arg_arithmetic(code: &Vec<ByteCode>) -> Option<Vec<ByteCode>> {
         // If there are not enough operations to loop forward, stop trying to optimize. Below logic assumes there
         // will always be at least 4 codes to inspect.
         if i > code.len() - 4 {
-            result.push(code[i].clone());
+            result.push(code[i]);
             i += 1;
             continue;
         }
@@ -128,25 +297,26 @@ fn optimize_2_arg_arithmetic(code: &Vec<ByteCode>) -> Option<Vec<ByteCode>> {
         if has_push_const && has_arithmetic {
             // v0 and v1 are the low indexes becuase this is looking foward from i. So, i will be the "bottom" of the stack
             // for this operation.
-            let v0 = code[i + 1].arg;
-            let v1 = code[i].arg;
-
-            let mut r = 0;
-            let mut do_replace = true;
-
-            match func_num {
-                builtins::ops::ADD => r = v1 + v0,
-                builtins::ops::SUBTRACT => r = v1 - v0,
-                builtins::ops::MULTIPLY => r = v1 * v0,
-                builtins::ops::DIVIDE => r = v1 / v0,
-                builtins::ops::MOD_ => r = v1 % v0,
-                _ => do_replace = false,
-            }
-
-            if do_replace {
+            let v0 = C::from_i32(code[i + 1].arg);
+            let v1 = C::from_i32(code[i].arg);
+
+            // Operations that would fail (such as dividing by zero) are left for the VM to report.
+            let folded = match func_num {
+                builtins::ops::ADD => mode.add(v1, v0).ok(),
+                builtins::ops::SUBTRACT => mode.subtract(v1, v0).ok(),
+                builtins::ops::MULTIPLY => mode.multiply(v1, v0).ok(),
+                builtins::ops::DIVIDE => mode.divide(v1, v0).ok(),
+                builtins::ops::MOD_ => mode.modulo(v1, v0).ok(),
+                _ => builtins::apply_logic_2_arg(func_num, v1.clone(), v0.clone())
+                    .or_else(|| builtins::apply_bitwise_2_arg(func_num, v1, v0)?.ok()),
+            };
+
+            // A constant is stored in an i32 argument, so larger results are left for the VM to compute.
+            if let Some(r) = folded.and_then(|x| x.to_i32()) {
                 result.push(ByteCode {
                     op_code: OpCode::PushConst,
                     arg: r,
+                    span: merge_spans(&code[i], &code[i + 3]),
                 });
                 was_replaced = true;
 
@@ -157,7 +327,7 @@ fn optimize_2_arg_arithmetic(code: &Vec<ByteCode>) -> Option<Vec<ByteCode>> {
 
         // No optimization was found looking forward, so add the current op to the result.
         if !was_replaced {
-            result.push(code[i].clone());
+            result.push(code[i]);
             i += 1;
         }
     }
@@ -171,14 +341,30 @@ fn optimize_2_arg_arithmetic(code: &Vec<ByteCode>) -> Option<Vec<ByteCode>> {
 
 fn is_two_arg_arithmetic(byte_code: &ByteCode, func_num: i32) -> bool {
     if byte_code.op_code == OpCode::Func {
-        match func_num {
+        matches!(
+            func_num,
             builtins::ops::ADD
-            | builtins::ops::SUBTRACT
-            | builtins::ops::MULTIPLY
-            | builtins::ops::DIVIDE
-            | builtins::ops::MOD_ => true,
-            _ => false,
-        }
+                | builtins::ops::SUBTRACT
+                | builtins::ops::MULTIPLY
+                | builtins::ops::DIVIDE
+                | builtins::ops::MOD_
+                | builtins::ops::EQ
+                | builtins::ops::NE
+                | builtins::ops::LT
+                | builtins::ops::LE
+                | builtins::ops::GT
+                | builtins::ops::GE
+                | builtins::ops::AND
+                | builtins::ops::OR
+                | builtins::ops::MIN
+                | builtins::ops::MAX
+                | builtins::ops::BIT_AND
+                | builtins::ops::BIT_OR
+                | builtins::ops::BIT_XOR
+                | builtins::ops::SHIFT_LEFT
+                | builtins::ops::SHIFT_RIGHT
+                | builtins::ops::SHIFT_RIGHT_LOGICAL
+        )
     } else {
         false
     }
@@ -187,7 +373,7 @@ fn is_two_arg_arithmetic(byte_code: &ByteCode, func_num: i32) -> bool {
 /// Optimize code like the following to a single constant. This assumes that "easy constant" optimization has been
 /// executed.
 ///
-/// ```
+/// ```text
 /// Push Constant <= v0 (stack top when func executing)
 /// Push Constant <= If this constant is an arithmetic function.
 /// Function Call
@@ -195,7 +381,10 @@ fn is_two_arg_arithmetic(byte_code: &ByteCode, func_num: i32) -> bool {
 ///
 /// This series of commands can be turned into a single constant because arithmetic on constants will always be
 /// a constant value. This will cover cases where two constants are "mathed" into a single constant.
-fn optimize_1_arg_arithmetic(code: &Vec<ByteCode>) -> Option<Vec<ByteCode>> {
+fn optimize_1_arg_arithmetic<C: CellValue>(
+    code: &[ByteCode],
+    mode: ArithmeticMode,
+) -> Option<Vec<ByteCode>> {
     if code.len() < 3 {
         return None;
     }
@@ -207,7 +396,7 @@ fn optimize_1_arg_arithmetic(code: &Vec<ByteCode>) -> Option<Vec<ByteCode>> {
         // If there are not enough operations to loop forward, stop trying to optimize. Below logic assumes there
         // will always be at least 3 codes to inspect.
         if i > code.len() - 3 {
-            result.push(code[i].clone());
+            result.push(code[i]);
             i += 1;
             continue;
         }
@@ -220,21 +409,24 @@ fn optimize_1_arg_arithmetic(code: &Vec<ByteCode>) -> Option<Vec<ByteCode>> {
 
         let mut was_replaced = false;
         if has_push_const && has_arithmetic {
-            let v0 = code[i].arg;
-            let mut r = 0;
-            let mut do_replace = true;
-
-            match func_num {
-                builtins::ops::DOUBLE_VAL => r = v0 * 2,
-                builtins::ops::NEGATE => r = v0 * -1,
-                builtins::ops::SQUARE => r = v0 * v0,
-                _ => do_replace = false,
-            }
-
-            if do_replace {
+            let v0 = C::from_i32(code[i].arg);
+
+            // Operations that would fail (such as overflowing) are left for the VM to report.
+            let folded = match func_num {
+                builtins::ops::DOUBLE_VAL => mode.multiply(v0, C::from_i32(2)).ok(),
+                builtins::ops::NEGATE => mode.negate(v0).ok(),
+                builtins::ops::SQUARE => mode.multiply(v0.clone(), v0).ok(),
+                builtins::ops::ABS => mode.abs(v0).ok(),
+                _ => builtins::apply_logic_1_arg(func_num, v0.clone())
+                    .or_else(|| builtins::apply_bitwise_1_arg(func_num, v0)?.ok()),
+            };
+
+            // A constant is stored in an i32 argument, so larger results are left for the VM to compute.
+            if let Some(r) = folded.and_then(|x| x.to_i32()) {
                 result.push(ByteCode {
                     op_code: OpCode::PushConst,
                     arg: r,
+                    span: merge_spans(&code[i], &code[i + 2]),
                 });
                 was_replaced = true;
 
@@ -244,7 +436,7 @@ fn optimize_1_arg_arithmetic(code: &Vec<ByteCode>) -> Option<Vec<ByteCode>> {
         }
 
         if !was_replaced {
-            result.push(code[i].clone());
+            result.push(code[i]);
             i += 1;
         }
     }
@@ -258,15 +450,201 @@ fn optimize_1_arg_arithmetic(code: &Vec<ByteCode>) -> Option<Vec<ByteCode>> {
 
 fn is_one_arg_arithmetic(byte_code: &ByteCode, func_num: i32) -> bool {
     if byte_code.op_code == OpCode::Func {
-        match func_num {
-            builtins::ops::DOUBLE_VAL | builtins::ops::NEGATE | builtins::ops::SQUARE => true,
-            _ => false,
-        }
+        matches!(
+            func_num,
+            builtins::ops::DOUBLE_VAL
+                | builtins::ops::NEGATE
+                | builtins::ops::SQUARE
+                | builtins::ops::NOT
+                | builtins::ops::ABS
+                | builtins::ops::SIGN
+                | builtins::ops::BIT_NOT
+                | builtins::ops::POPCOUNT
+                | builtins::ops::LEADING_ZEROS
+        )
     } else {
         false
     }
 }
 
+/// Replace reads of global variables with constants, when the block already set the global to a constant.
+///
+/// ```text
+/// Push Constant <= value
+/// Push Constant <= slot
+/// Push Constant <= set global
+/// Function Call
+/// ...
+/// Push Constant <= slot
+/// Push Constant <= get global
+/// Function Call <= These three are replaced with a push of the value.
+/// ```
+///
+/// Builtins other than set global do not change globals, but host functions can, so the known values are forgotten at
+/// any other function call.
+fn optimize_globals(code: &[ByteCode]) -> Option<Vec<ByteCode>> {
+    let mut result: Vec<ByteCode> = Vec::new();
+    let mut known: HashMap<i32, i32> = HashMap::new();
+
+    for byte_code in code.iter() {
+        if byte_code.op_code == OpCode::Func {
+            let len = result.len();
+            // The constants right before the func, with the function number last.
+            let arg = |back: usize| -> Option<i32> {
+                if back <= len && result[len - back].op_code == OpCode::PushConst {
+                    Some(result[len - back].arg)
+                } else {
+                    None
+                }
+            };
+
+            match arg(1) {
+                Some(builtins::ops::GET_GLOBAL) => {
+                    if let Some(value) = arg(2).and_then(|slot| known.get(&slot).copied()) {
+                        let span = merge_spans(&result[len - 2], byte_code);
+                        result.truncate(len - 2);
+                        result.push(ByteCode {
+                            op_code: OpCode::PushConst,
+                            arg: value,
+                            span,
+                        });
+                        continue;
+                    }
+                }
+                Some(builtins::ops::SET_GLOBAL) => match (arg(2), arg(3)) {
+                    (Some(slot), Some(value)) => {
+                        known.insert(slot, value);
+                    }
+                    (Some(slot), None) => {
+                        known.remove(&slot);
+                    }
+                    _ => known.clear(),
+                },
+                Some(func_num) if func_num <= builtins::BUILTIN_MAX => {}
+                _ => known.clear(),
+            }
+        }
+
+        result.push(*byte_code);
+    }
+
+    if result.len() < code.len() {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+/// Rearrange constants with stack builtins at compile time. This assumes that "easy constant" optimization has been
+/// executed.
+///
+/// ```text
+/// Push Constant <= a
+/// Push Constant <= b
+/// Push Constant <= If this constant is a stack function, such as swap.
+/// Function Call
+/// ```
+///
+/// When every item the function uses was pushed as a constant in the same block, the function and the constants are
+/// replaced by the constants it would leave on the stack (b, a for swap). Functions that depend on the whole stack,
+/// like depth and clear, are left alone.
+fn optimize_stack_ops(code: &[ByteCode]) -> Option<Vec<ByteCode>> {
+    let mut result: Vec<ByteCode> = Vec::new();
+
+    for byte_code in code.iter() {
+        if byte_code.op_code == OpCode::Func {
+            // The run of constants right before the func, with the function number last.
+            let const_count = result
+                .iter()
+                .rev()
+                .take_while(|x| x.op_code == OpCode::PushConst)
+                .count();
+
+            if const_count > 0 {
+                let start = result.len() - const_count;
+                let func_num = result[result.len() - 1].arg;
+                let values: Vec<i32> = result[start..result.len() - 1]
+                    .iter()
+                    .map(|x| x.arg)
+                    .collect();
+
+                if let Some((used, folded)) = fold_stack_op(func_num, &values) {
+                    let first = result.len() - 1 - used;
+                    let span = merge_spans(&result[first], byte_code);
+
+                    result.truncate(first);
+                    result.extend(folded.into_iter().map(|arg| ByteCode {
+                        op_code: OpCode::PushConst,
+                        arg,
+                        span,
+                    }));
+                    continue;
+                }
+            }
+        }
+
+        result.push(*byte_code);
+    }
+
+    if result.len() < code.len() {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+/// Apply the stack function `func_num` to constants, with the top of the stack last. Returns how many of the
+/// constants were used and the values left in their place, or `None` if the function cannot be folded.
+fn fold_stack_op(func_num: i32, values: &[i32]) -> Option<(usize, Vec<i32>)> {
+    let top = |count: usize| -> Option<&[i32]> {
+        if values.len() >= count {
+            Some(&values[values.len() - count..])
+        } else {
+            None
+        }
+    };
+
+    match func_num {
+        builtins::ops::SWAP => top(2).map(|v| (2, vec![v[1], v[0]])),
+        builtins::ops::OVER => top(2).map(|v| (2, vec![v[0], v[1], v[0]])),
+        builtins::ops::ROT => top(3).map(|v| (3, vec![v[1], v[2], v[0]])),
+        builtins::ops::PICK | builtins::ops::ROLL | builtins::ops::DROP_N => {
+            // Negative counts and counts deeper than the constants are left for the VM.
+            let n = usize::try_from(*values.last()?).ok()?;
+            let items = match func_num {
+                builtins::ops::DROP_N => top(n + 1)?,
+                _ => top(n + 2)?,
+            };
+            let items = &items[..items.len() - 1];
+
+            let folded = match func_num {
+                builtins::ops::PICK => {
+                    let mut folded = items.to_vec();
+                    folded.push(items[0]);
+                    folded
+                }
+                builtins::ops::ROLL => {
+                    let mut folded = items.to_vec();
+                    folded.rotate_left(1);
+                    folded
+                }
+                _ => Vec::new(),
+            };
+            Some((items.len() + 1, folded))
+        }
+        _ => None,
+    }
+}
+
+/// Get the span covering the source of two operations that are being replaced. If either operation has no span, the
+/// result has no span.
+fn merge_spans(first: &ByteCode, last: &ByteCode) -> Option<Span> {
+    match (first.span, last.span) {
+        (Some(a), Some(b)) => Some(a.to(b)),
+        _ => None,
+    }
+}
+
 // TODO: Tests
 #[rustfmt::skip]
 #[cfg(test)]
@@ -276,12 +654,12 @@ mod tests {
     #[test]
     fn test_easy_constant_1() {
         let byte_code = vec!(
-            ByteCode{ op_code: OpCode::Push0, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Label, arg: 1 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 }
+            ByteCode::new(OpCode::Push0, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Label, 1),
+            ByteCode::new(OpCode::Bump, 0)
         );
 
         let optimized = optimize_easy_constants(&byte_code);
@@ -301,12 +679,12 @@ mod tests {
     #[test]
     fn test_1_arg_arithmetic() {
         let byte_code = vec!(
-            ByteCode{ op_code: OpCode::PushConst, arg: 3 },
-            ByteCode{ op_code: OpCode::PushConst, arg: 8 },
-            ByteCode{ op_code: OpCode::Func, arg: 0 }
+            ByteCode::new(OpCode::PushConst, 3),
+            ByteCode::new(OpCode::PushConst, 8),
+            ByteCode::new(OpCode::Func, 0)
         );
 
-        let optimized = optimize_1_arg_arithmetic(&byte_code).unwrap();
+        let optimized = optimize_1_arg_arithmetic::<i32>(&byte_code, ArithmeticMode::Checked).unwrap();
 
         assert_eq!(1, optimized.len());
 
@@ -317,26 +695,26 @@ mod tests {
     #[test]
     fn test_1_arg_arithmetic_no_op() {
         let byte_code = vec!(
-            ByteCode{ op_code: OpCode::PushConst, arg: 3 },
-            ByteCode{ op_code: OpCode::PushConst, arg: 8 },
-            ByteCode{ op_code: OpCode::Label, arg: 8 },
-            ByteCode{ op_code: OpCode::Func, arg: 0 }
+            ByteCode::new(OpCode::PushConst, 3),
+            ByteCode::new(OpCode::PushConst, 8),
+            ByteCode::new(OpCode::Label, 8),
+            ByteCode::new(OpCode::Func, 0)
         );
 
-        let optimized = optimize_1_arg_arithmetic(&byte_code);
+        let optimized = optimize_1_arg_arithmetic::<i32>(&byte_code, ArithmeticMode::Checked);
         assert!(optimized.is_none());
     }
 
     #[test]
     fn test_2_arg_arithmetic() {
         let byte_code = vec!(
-            ByteCode{ op_code: OpCode::PushConst, arg: 10 },
-            ByteCode{ op_code: OpCode::PushConst, arg: 3 },
-            ByteCode{ op_code: OpCode::PushConst, arg: 3 },
-            ByteCode{ op_code: OpCode::Func, arg: 0 }
+            ByteCode::new(OpCode::PushConst, 10),
+            ByteCode::new(OpCode::PushConst, 3),
+            ByteCode::new(OpCode::PushConst, 3),
+            ByteCode::new(OpCode::Func, 0)
         );
 
-        let optimized = optimize_2_arg_arithmetic(&byte_code).unwrap();
+        let optimized = optimize_2_arg_arithmetic::<i32>(&byte_code, ArithmeticMode::Checked).unwrap();
 
         assert_eq!(1, optimized.len());
 
@@ -347,14 +725,14 @@ mod tests {
     #[test]
     fn test_2_arg_arithmetic_no_op() {
         let byte_code = vec!(
-            ByteCode{ op_code: OpCode::PushConst, arg: 10 },
-            ByteCode{ op_code: OpCode::PushConst, arg: 3 },
-            ByteCode{ op_code: OpCode::PushConst, arg: 3 },
-            ByteCode{ op_code: OpCode::Label, arg: 2 },
-            ByteCode{ op_code: OpCode::Func, arg: 0 }
+            ByteCode::new(OpCode::PushConst, 10),
+            ByteCode::new(OpCode::PushConst, 3),
+            ByteCode::new(OpCode::PushConst, 3),
+            ByteCode::new(OpCode::Label, 2),
+            ByteCode::new(OpCode::Func, 0)
         );
 
-        let optimized = optimize_2_arg_arithmetic(&byte_code);
+        let optimized = optimize_2_arg_arithmetic::<i32>(&byte_code, ArithmeticMode::Checked);
         assert!(optimized.is_none());
     }
 
@@ -363,38 +741,38 @@ mod tests {
         // Test that constants are squashed and multiple arithmetic operations are done. This op code will do (3 * 4)^2
         let byte_code = vec!(
             // Push and bump to 3
-            ByteCode{ op_code: OpCode::Push0, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
+            ByteCode::new(OpCode::Push0, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
 
             // Push and bump to 4
-            ByteCode{ op_code: OpCode::Push0, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
+            ByteCode::new(OpCode::Push0, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
 
             // Push and bump to 4 for multiply function
-            ByteCode{ op_code: OpCode::Push0, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Func, arg: 0 },
+            ByteCode::new(OpCode::Push0, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Func, 0),
 
             // Push and bump to 9 for square
-            ByteCode{ op_code: OpCode::Push0, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Bump, arg: 0 },
-            ByteCode{ op_code: OpCode::Func, arg: 0 },
+            ByteCode::new(OpCode::Push0, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Func, 0),
         );
 
         let optimized = optimize(&byte_code);
@@ -404,4 +782,290 @@ mod tests {
         assert_eq!(OpCode::PushConst, optimized[0].op_code);
         assert_eq!(144, optimized[0].arg);
     }
+
+    #[test]
+    fn test_optimized_span() {
+        // "SRR SRRR SRRE" folds to a single constant covering all of the source.
+        let span = |start, column| Some(Span::new(start, start + 1, 1, column));
+        let byte_code = vec!(
+            ByteCode { op_code: OpCode::Push0, arg: 0, span: span(0, 1) },
+            ByteCode { op_code: OpCode::Bump, arg: 0, span: span(1, 2) },
+            ByteCode { op_code: OpCode::Bump, arg: 0, span: span(2, 3) },
+            ByteCode { op_code: OpCode::Push0, arg: 0, span: span(4, 5) },
+            ByteCode { op_code: OpCode::Bump, arg: 0, span: span(5, 6) },
+            ByteCode { op_code: OpCode::Bump, arg: 0, span: span(6, 7) },
+            ByteCode { op_code: OpCode::Bump, arg: 0, span: span(7, 8) },
+            ByteCode { op_code: OpCode::Push0, arg: 0, span: span(9, 10) },
+            ByteCode { op_code: OpCode::Bump, arg: 0, span: span(10, 11) },
+            ByteCode { op_code: OpCode::Bump, arg: 0, span: span(11, 12) },
+            ByteCode { op_code: OpCode::Func, arg: 0, span: span(12, 13) },
+        );
+
+        let optimized = optimize(&byte_code);
+
+        assert_eq!(1, optimized.len());
+        assert_eq!(5, optimized[0].arg);
+        assert_eq!(Some(Span::new(0, 13, 1, 1)), optimized[0].span);
+    }
+
+    #[test]
+    fn test_easy_constant_at_end() {
+        let byte_code = vec!(
+            ByteCode::new(OpCode::Pop, 0),
+            ByteCode::new(OpCode::Push0, 0),
+            ByteCode::new(OpCode::Bump, 0),
+            ByteCode::new(OpCode::Bump, 0),
+        );
+
+        let optimized = optimize_easy_constants(&byte_code);
+
+        assert_eq!(vec!(ByteCode::new(OpCode::Pop, 0), ByteCode::new(OpCode::PushConst, 2)), optimized);
+    }
+
+    #[test]
+    fn test_build_cfg() {
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, 1),
+            ByteCode::new(OpCode::Label, 0),
+            ByteCode::new(OpCode::PushConst, 1),
+            ByteCode::new(OpCode::Jump, 1),
+            ByteCode::new(OpCode::PushConst, 0),
+            ByteCode::new(OpCode::Jump, 0),
+            ByteCode::new(OpCode::Label, 1),
+        );
+
+        let cfg = ControlFlowGraph::build(&byte_code);
+
+        let blocks: Vec<(usize, Vec<usize>)> = cfg.blocks.iter().map(|b| (b.code.len(), b.successors.clone())).collect();
+        assert_eq!(vec!((1, vec!(1)), (3, vec!(3, 2)), (2, vec!(1)), (1, vec!())), blocks);
+    }
+
+    #[test]
+    fn test_build_cfg_call() {
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, 3),
+            ByteCode::new(OpCode::Jump, 0),
+            ByteCode::new(OpCode::PushConst, 0),
+            ByteCode::new(OpCode::Jump, 1),
+            ByteCode::new(OpCode::Label, 0),
+            ByteCode::new(OpCode::PushConst, 4),
+            ByteCode::new(OpCode::Jump, 0),
+            ByteCode::new(OpCode::Label, 1),
+        );
+
+        let cfg = ControlFlowGraph::build(&byte_code);
+
+        // The call continues after the jump when it returns. The return has no successors of its own.
+        let blocks: Vec<(usize, Vec<usize>)> = cfg.blocks.iter().map(|b| (b.code.len(), b.successors.clone())).collect();
+        assert_eq!(vec!((2, vec!(2, 1)), (2, vec!(3)), (3, vec!()), (1, vec!())), blocks);
+    }
+
+    #[test]
+    fn test_no_folding_across_label() {
+        // The label is a jump target, so the constants before it must not be folded with the add after it.
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, 1),
+            ByteCode::new(OpCode::PushConst, 2),
+            ByteCode::new(OpCode::Label, 0),
+            ByteCode::new(OpCode::PushConst, 2),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        assert_eq!(byte_code, optimize(&byte_code));
+    }
+
+    #[test]
+    fn test_remove_unreachable_blocks() {
+        // Always jump to label 1, skipping the code in between that has no label to reach it.
+        let byte_code = vec!(
+            ByteCode::new(OpCode::Push0, 0),
+            ByteCode::new(OpCode::Jump, 1),
+            ByteCode::new(OpCode::PushConst, 5),
+            ByteCode::new(OpCode::Label, 1),
+            ByteCode::new(OpCode::PushConst, 7),
+        );
+
+        let optimized = optimize(&byte_code);
+
+        assert_eq!(
+            vec!(
+                ByteCode::new(OpCode::Push0, 0),
+                ByteCode::new(OpCode::Jump, 1),
+                ByteCode::new(OpCode::Label, 1),
+                ByteCode::new(OpCode::PushConst, 7),
+            ),
+            optimized
+        );
+    }
+
+    #[test]
+    fn test_no_folding_failed_arithmetic() {
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, 1),
+            ByteCode::new(OpCode::PushConst, 0),
+            ByteCode::new(OpCode::PushConst, builtins::ops::DIVIDE),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        assert!(optimize_2_arg_arithmetic::<i32>(&byte_code, ArithmeticMode::Checked).is_none());
+    }
+
+    #[test]
+    fn test_fold_with_arithmetic_mode() {
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, i32::MAX),
+            ByteCode::new(OpCode::PushConst, 1),
+            ByteCode::new(OpCode::PushConst, builtins::ops::ADD),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        assert!(optimize_2_arg_arithmetic::<i32>(&byte_code, ArithmeticMode::Checked).is_none());
+        assert_eq!(vec!(ByteCode::new(OpCode::PushConst, i32::MIN)), optimize_2_arg_arithmetic::<i32>(&byte_code, ArithmeticMode::Wrapping).unwrap());
+        assert_eq!(vec!(ByteCode::new(OpCode::PushConst, i32::MAX)), optimize_2_arg_arithmetic::<i32>(&byte_code, ArithmeticMode::Saturating).unwrap());
+    }
+
+    #[test]
+    fn test_fold_with_cell_type() {
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, i32::MAX),
+            ByteCode::new(OpCode::PushConst, 1),
+            ByteCode::new(OpCode::PushConst, builtins::ops::ADD),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        // The sum fits in an i64, but not in the constant's argument.
+        assert!(optimize_2_arg_arithmetic::<i64>(&byte_code, ArithmeticMode::Wrapping).is_none());
+        assert!(optimize_2_arg_arithmetic::<num_bigint::BigInt>(&byte_code, ArithmeticMode::Checked).is_none());
+    }
+
+    #[test]
+    fn test_fold_stack_ops() {
+        let byte_code = vec!(
+            ByteCode::new(OpCode::Pop, 0),
+            ByteCode::new(OpCode::PushConst, 1),
+            ByteCode::new(OpCode::PushConst, 5),
+            ByteCode::new(OpCode::PushConst, builtins::ops::SWAP),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, builtins::ops::SUBTRACT),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        assert_eq!(vec!(ByteCode::new(OpCode::Pop, 0), ByteCode::new(OpCode::PushConst, 4)), optimize_block::<i32>(&byte_code, ArithmeticMode::Checked));
+    }
+
+    #[test]
+    fn test_fold_stack_op() {
+        assert_eq!(Some((3, vec!(2, 3, 1))), fold_stack_op(builtins::ops::ROT, &[1, 2, 3]));
+        assert_eq!(Some((4, vec!(7, 8, 9, 7))), fold_stack_op(builtins::ops::PICK, &[7, 8, 9, 2]));
+        assert_eq!(Some((4, vec!(8, 9, 7))), fold_stack_op(builtins::ops::ROLL, &[6, 7, 8, 9, 2]));
+        assert_eq!(Some((3, vec!())), fold_stack_op(builtins::ops::DROP_N, &[6, 7, 8, 2]));
+
+        // Items below the constants, negative counts and functions that use the whole stack are not folded.
+        assert_eq!(None, fold_stack_op(builtins::ops::PICK, &[8, 9, 2]));
+        assert_eq!(None, fold_stack_op(builtins::ops::DROP_N, &[8, 9, -1]));
+        assert_eq!(None, fold_stack_op(builtins::ops::DEPTH, &[8, 9]));
+    }
+
+    #[test]
+    fn test_fold_comparisons() {
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, 3),
+            ByteCode::new(OpCode::PushConst, 4),
+            ByteCode::new(OpCode::PushConst, builtins::ops::LT),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, builtins::ops::NOT),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        assert_eq!(vec!(ByteCode::new(OpCode::PushConst, 0)), optimize_block::<i32>(&byte_code, ArithmeticMode::Checked));
+
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, i32::MIN),
+            ByteCode::new(OpCode::PushConst, builtins::ops::ABS),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        assert!(optimize_1_arg_arithmetic::<i32>(&byte_code, ArithmeticMode::Checked).is_none());
+        assert_eq!(vec!(ByteCode::new(OpCode::PushConst, i32::MAX)), optimize_1_arg_arithmetic::<i32>(&byte_code, ArithmeticMode::Saturating).unwrap());
+    }
+
+    #[test]
+    fn test_fold_bitwise() {
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, -1),
+            ByteCode::new(OpCode::PushConst, 28),
+            ByteCode::new(OpCode::PushConst, builtins::ops::SHIFT_RIGHT_LOGICAL),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, builtins::ops::POPCOUNT),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        assert_eq!(vec!(ByteCode::new(OpCode::PushConst, 4)), optimize_block::<i32>(&byte_code, ArithmeticMode::Checked));
+
+        // The logical shift of a negative big integer is an error, so it is left for the VM.
+        assert_eq!(byte_code, optimize_block::<num_bigint::BigInt>(&byte_code, ArithmeticMode::Checked));
+    }
+
+    #[test]
+    fn test_forward_globals() {
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, 5),
+            ByteCode::new(OpCode::PushConst, 2),
+            ByteCode::new(OpCode::PushConst, builtins::ops::SET_GLOBAL),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, builtins::ops::OUTPUT),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, 2),
+            ByteCode::new(OpCode::PushConst, builtins::ops::GET_GLOBAL),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        let expected = vec!(
+            ByteCode::new(OpCode::PushConst, 5),
+            ByteCode::new(OpCode::PushConst, 2),
+            ByteCode::new(OpCode::PushConst, builtins::ops::SET_GLOBAL),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, builtins::ops::OUTPUT),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, 5),
+        );
+
+        assert_eq!(Some(expected), optimize_globals(&byte_code));
+    }
+
+    #[test]
+    fn test_forward_globals_unknown() {
+        // A host function can change any global.
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, 5),
+            ByteCode::new(OpCode::PushConst, 2),
+            ByteCode::new(OpCode::PushConst, builtins::ops::SET_GLOBAL),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, 100),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, 2),
+            ByteCode::new(OpCode::PushConst, builtins::ops::GET_GLOBAL),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        assert_eq!(None, optimize_globals(&byte_code));
+
+        // The value is not known, so the earlier constant is forgotten.
+        let byte_code = vec!(
+            ByteCode::new(OpCode::PushConst, 5),
+            ByteCode::new(OpCode::PushConst, 2),
+            ByteCode::new(OpCode::PushConst, builtins::ops::SET_GLOBAL),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, builtins::ops::INPUT),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, 2),
+            ByteCode::new(OpCode::PushConst, builtins::ops::SET_GLOBAL),
+            ByteCode::new(OpCode::Func, 0),
+            ByteCode::new(OpCode::PushConst, 2),
+            ByteCode::new(OpCode::PushConst, builtins::ops::GET_GLOBAL),
+            ByteCode::new(OpCode::Func, 0),
+        );
+
+        assert_eq!(None, optimize_globals(&byte_code));
+    }
 }
@@ -0,0 +1,295 @@
+//! Loading native plugins through the C ABI described in `include/shrek_plugin.h`.
+//!
+//! A plugin is a shared library exporting `shrek_plugin_abi_version` and `shrek_plugin_functions`. Each function in
+//! the plugin's table is registered as a host function on a `ShrekVM` and talks to the VM's stack through the
+//! callbacks in [`ShrekApi`].
+//!
+//! Values cross the C interface as 32 bit integers. On a VM with a wider cell type, popping or peeking a value that
+//! does not fit fails and leaves the value on the stack.
+
+use crate::cell::CellValue;
+use crate::shrek_vm::{ShrekRuntimeError, ShrekVM, VmResult};
+
+use libloading::{Library, Symbol};
+use std::ffi::{c_void, CStr};
+use std::fmt;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::rc::Rc;
+use std::slice;
+
+/// Version of the plugin interface. Plugins reporting a different version are rejected.
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+/// Callbacks given to native functions for manipulating the VM's stack.
+#[repr(C)]
+pub struct ShrekApi {
+    pub vm: *mut c_void,
+    pub push: extern "C" fn(vm: *mut c_void, value: i32),
+    pub pop: extern "C" fn(vm: *mut c_void, value: *mut i32) -> i32,
+    pub peek: extern "C" fn(vm: *mut c_void, value: *mut i32) -> i32,
+    pub count: extern "C" fn(vm: *mut c_void) -> usize,
+}
+
+/// A native function. Returns 0 on success. Any other value is reported as a runtime error.
+pub type NativeFunction = unsafe extern "C" fn(api: *const ShrekApi) -> i32;
+
+/// An entry in the table returned by a plugin's `shrek_plugin_functions`.
+#[repr(C)]
+pub struct PluginFunction {
+    pub name: *const c_char,
+    pub func_num: i32,
+    pub arity: u32,
+    pub func: NativeFunction,
+}
+
+#[derive(Debug, Clone)]
+pub struct PluginError {
+    pub message: String,
+}
+
+pub type PluginResult<T> = Result<T, PluginError>;
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type FunctionsFn = unsafe extern "C" fn(count: *mut usize) -> *const PluginFunction;
+
+/// Load the plugin at `path` and register all of its functions on `vm`. Returns the number of functions registered.
+///
+/// # Safety
+///
+/// Loading a plugin runs arbitrary native code. The library must implement the interface in
+/// `include/shrek_plugin.h`.
+pub unsafe fn load_plugin<C, P>(vm: &mut ShrekVM<C>, path: P) -> PluginResult<usize>
+where
+    C: CellValue,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+
+    let library = Library::new(path)
+        .map_err(|e| PluginError::new(&format!("cannot load plugin {}: {}", path.display(), e)))?;
+    let library = Rc::new(library);
+
+    let abi_version: Symbol<AbiVersionFn> =
+        get_symbol(&library, path, b"shrek_plugin_abi_version\0")?;
+    let version = abi_version();
+    if version != PLUGIN_ABI_VERSION {
+        return Err(PluginError::new(&format!(
+            "plugin {} has ABI version {}, expected {}",
+            path.display(),
+            version,
+            PLUGIN_ABI_VERSION
+        )));
+    }
+
+    let plugin_functions: Symbol<FunctionsFn> =
+        get_symbol(&library, path, b"shrek_plugin_functions\0")?;
+    let mut count = 0;
+    let table = plugin_functions(&mut count);
+    let functions = if table.is_null() || count == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(table, count)
+    };
+
+    for function in functions {
+        let name = if function.name.is_null() {
+            String::from("<unnamed>")
+        } else {
+            CStr::from_ptr(function.name).to_string_lossy().into_owned()
+        };
+
+        // Each registered function keeps the library loaded for as long as the VM can call it.
+        let keep_loaded = Rc::clone(&library);
+        let func_num = function.func_num;
+        let func = function.func;
+        vm.register_function(func_num, function.arity as usize, move |vm| {
+            let _library = &keep_loaded;
+            call_native(vm, func_num, func)
+        })
+        .map_err(|e| {
+            PluginError::new(&format!(
+                "cannot register {} from plugin {}: {}",
+                name,
+                path.display(),
+                e.message
+            ))
+        })?;
+    }
+
+    Ok(functions.len())
+}
+
+/// Register a single native function on `vm` without loading a library.
+///
+/// # Safety
+///
+/// `func` must only use the callbacks in the `ShrekApi` it is given and must not keep the pointer after returning.
+pub unsafe fn register_native_function<C: CellValue>(
+    vm: &mut ShrekVM<C>,
+    func_num: i32,
+    arity: usize,
+    func: NativeFunction,
+) -> VmResult<()> {
+    vm.register_function(func_num, arity, move |vm| call_native(vm, func_num, func))
+}
+
+unsafe fn get_symbol<'lib, T>(
+    library: &'lib Library,
+    path: &Path,
+    symbol: &[u8],
+) -> PluginResult<Symbol<'lib, T>> {
+    library.get(symbol).map_err(|e| {
+        PluginError::new(&format!(
+            "plugin {} is missing {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&symbol[..symbol.len() - 1]),
+            e
+        ))
+    })
+}
+
+fn call_native<C: CellValue>(
+    vm: &mut ShrekVM<C>,
+    func_num: i32,
+    func: NativeFunction,
+) -> VmResult<()> {
+    let api = ShrekApi {
+        vm: vm as *mut ShrekVM<C> as *mut c_void,
+        push: api_push::<C>,
+        pop: api_pop::<C>,
+        peek: api_peek::<C>,
+        count: api_count::<C>,
+    };
+
+    let code = unsafe { func(&api) };
+    if code == 0 {
+        Ok(())
+    } else {
+        Err(ShrekRuntimeError::new(&format!(
+            "native function {} failed with code {}",
+            func_num, code
+        )))
+    }
+}
+
+// The callbacks below are only handed out through a ShrekApi created by call_native, so the vm pointer always refers
+// to the VM that is currently executing the native function, with the same cell type.
+
+extern "C" fn api_push<C: CellValue>(vm: *mut c_void, value: i32) {
+    let vm = unsafe { &mut *(vm as *mut ShrekVM<C>) };
+    vm.push(C::from_i32(value));
+}
+
+extern "C" fn api_pop<C: CellValue>(vm: *mut c_void, value: *mut i32) -> i32 {
+    let result = api_peek::<C>(vm, value);
+    if result == 0 {
+        let vm = unsafe { &mut *(vm as *mut ShrekVM<C>) };
+        let _ = vm.pop();
+    }
+    result
+}
+
+extern "C" fn api_peek<C: CellValue>(vm: *mut c_void, value: *mut i32) -> i32 {
+    let vm = unsafe { &*(vm as *const ShrekVM<C>) };
+    match vm.peek().map(|x| x.to_i32()) {
+        Ok(Some(x)) => {
+            if !value.is_null() {
+                unsafe { *value = x };
+            }
+            0
+        }
+        // The value does not fit in 32 bits.
+        Ok(None) => 2,
+        Err(_) => 1,
+    }
+}
+
+extern "C" fn api_count<C: CellValue>(vm: *mut c_void) -> usize {
+    let vm = unsafe { &*(vm as *const ShrekVM<C>) };
+    vm.count()
+}
+
+impl PluginError {
+    fn new(message: &str) -> PluginError {
+        PluginError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Plugin Error: {}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::byte_code::{ByteCode, OpCode};
+    use std::io;
+
+    unsafe extern "C" fn add_ten(api: *const ShrekApi) -> i32 {
+        let api = &*api;
+        let mut v0 = 0;
+        if (api.pop)(api.vm, &mut v0) != 0 {
+            return 1;
+        }
+        (api.push)(api.vm, v0 + 10);
+        0
+    }
+
+    unsafe extern "C" fn fail(_api: *const ShrekApi) -> i32 {
+        7
+    }
+
+    fn call(func_num: i32) -> Vec<ByteCode> {
+        vec![
+            ByteCode::new(OpCode::PushConst, 5),
+            ByteCode::new(OpCode::PushConst, func_num),
+            ByteCode::new(OpCode::Func, 0),
+        ]
+    }
+
+    #[test]
+    fn test_native_function() {
+        let mut vm = ShrekVM::<i32>::with_io(call(64), io::empty(), io::sink());
+        unsafe { register_native_function(&mut vm, 64, 1, add_ten).unwrap() };
+        assert_eq!(15, vm.run().unwrap());
+    }
+
+    #[test]
+    fn test_native_function_failure() {
+        let mut vm = ShrekVM::<i32>::with_io(call(64), io::empty(), io::sink());
+        unsafe { register_native_function(&mut vm, 64, 0, fail).unwrap() };
+
+        let err = vm.run().unwrap_err();
+        assert!(err.message.contains("code 7"));
+    }
+
+    #[test]
+    fn test_load_missing_plugin() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+        let result = unsafe { load_plugin(&mut vm, "does/not/exist.so") };
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_native_function_wide_value() {
+        // 5 * 2^31 does not fit in the 32 bit values passed to native functions.
+        let mut code = vec![
+            ByteCode::new(OpCode::PushConst, 5),
+            ByteCode::new(OpCode::PushConst, i32::MAX),
+            ByteCode::new(OpCode::PushConst, crate::builtins::ops::MULTIPLY),
+            ByteCode::new(OpCode::Func, 0),
+        ];
+        code.extend(call(64).into_iter().skip(1));
+
+        let mut vm = ShrekVM::<i64>::with_io(code, io::empty(), io::sink());
+        unsafe { register_native_function(&mut vm, 64, 1, add_ten).unwrap() };
+
+        assert!(vm.run().is_err());
+        assert_eq!(&[5 * i32::MAX as i64], vm.stack());
+    }
+}
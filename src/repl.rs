@@ -0,0 +1,287 @@
+//! Interactive read-eval-print loop.
+//!
+//! Each input is tokenized and parsed on its own, then compiled with a code generator that is kept for the whole
+//! session so label numbers stay consistent. The compiled code is appended to a single `ShrekVM`, so the stack and
+//! labels from earlier inputs remain available. Input that jumps to a label that has not been defined yet is held
+//! until the label is defined (or an empty line is entered), which allows loops to be typed over several lines.
+
+use shrek_lang_rust::byte_code::ByteCode;
+use shrek_lang_rust::cell::CellValue;
+use shrek_lang_rust::disasm::disassemble;
+use shrek_lang_rust::linker::Linker;
+use shrek_lang_rust::shrek_parser::{CodeGenerator, SyntaxNode, SyntaxTree, TokenType, Tokenizer};
+use shrek_lang_rust::shrek_vm::ShrekVM;
+
+use std::collections::HashSet;
+use std::io;
+use std::mem;
+
+const PROMPT: &str = "shrek> ";
+const CONTINUE_PROMPT: &str = "   ... ";
+
+const HELP: &str = "\
+Enter SHREK code to run it. The stack is shown after each input.
+Input that jumps to an undefined label continues on the next line. An empty line runs it anyway.
+
+:stack   Show the stack
+:reset   Clear the stack and all code
+:disasm  Show the byte code entered so far
+:help    Show this message
+:quit    Exit the REPL";
+
+pub struct Repl<'io, C> {
+    vm: ShrekVM<'io, C>,
+    tokenizer: Tokenizer,
+    generator: CodeGenerator,
+    linker: Linker,
+    defined_labels: HashSet<String>,
+    pending: Vec<SyntaxNode>,
+}
+
+impl<'io, C: CellValue> Repl<'io, C> {
+    /// Create a REPL around `vm`. Input is read from and output written to the VM's streams.
+    pub fn new(vm: ShrekVM<'io, C>) -> Repl<'io, C> {
+        Repl {
+            vm,
+            tokenizer: Tokenizer::new(),
+            generator: CodeGenerator::new(),
+            linker: Linker::new(),
+            defined_labels: HashSet::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Run until end of input or `:quit`.
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let prompt = if self.pending.is_empty() {
+                PROMPT
+            } else {
+                CONTINUE_PROMPT
+            };
+            write!(self.vm.output(), "{}", prompt)?;
+            self.vm.output().flush()?;
+
+            let mut line = String::new();
+            if self.vm.input().read_line(&mut line)? == 0 {
+                writeln!(self.vm.output())?;
+                return Ok(());
+            }
+
+            if !self.handle_line(&line)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handle a single line of input. Returns false when the REPL should exit.
+    fn handle_line(&mut self, line: &str) -> io::Result<bool> {
+        let trimmed = line.trim();
+
+        if trimmed.starts_with(':') {
+            return self.meta_command(trimmed);
+        }
+
+        if trimmed.is_empty() {
+            // An empty line forces pending input to run, even if it jumps to undefined labels.
+            if !self.pending.is_empty() {
+                self.execute()?;
+            }
+            return Ok(true);
+        }
+
+        let tree = match self
+            .tokenizer
+            .tokenize(line)
+            .and_then(|tokens| SyntaxTree::generate(&tokens))
+        {
+            Ok(x) => x,
+            Err(err) => {
+                writeln!(self.vm.output(), "{}", err.render("<repl>", line))?;
+                return Ok(true);
+            }
+        };
+
+        self.pending.extend(tree.tree);
+        if !self.has_undefined_jumps() {
+            self.execute()?;
+        }
+
+        Ok(true)
+    }
+
+    fn meta_command(&mut self, command: &str) -> io::Result<bool> {
+        match command {
+            ":stack" => self.print_stack()?,
+            ":reset" => {
+                self.vm.reset();
+                self.generator = CodeGenerator::new();
+                self.linker = Linker::new();
+                self.defined_labels.clear();
+                self.pending.clear();
+                writeln!(self.vm.output(), "Reset.")?;
+            }
+            ":disasm" => self.print_disasm()?,
+            ":help" => writeln!(self.vm.output(), "{}", HELP)?,
+            ":quit" | ":q" => return Ok(false),
+            _ => writeln!(
+                self.vm.output(),
+                "Unknown command {}. Type :help for help.",
+                command
+            )?,
+        }
+
+        Ok(true)
+    }
+
+    /// Check if pending input jumps to a label that has not been defined in the session or the pending input.
+    fn has_undefined_jumps(&self) -> bool {
+        let pending_labels: HashSet<&str> = self
+            .pending
+            .iter()
+            .filter(|node| node.token.token_type == TokenType::Label)
+            .map(|node| node.token.value.as_str())
+            .collect();
+
+        self.pending
+            .iter()
+            .flat_map(|node| node.children.iter())
+            .filter(|child| child.token.token_type == TokenType::Label)
+            .any(|child| {
+                let label = child.token.value.as_str();
+                !pending_labels.contains(label) && !self.defined_labels.contains(label)
+            })
+    }
+
+    /// Compile and run all pending input.
+    fn execute(&mut self) -> io::Result<()> {
+        let tree = SyntaxTree {
+            tree: mem::take(&mut self.pending),
+        };
+
+        let byte_code = match self.generator.generate(&tree) {
+            Ok(x) => x,
+            Err(err) => {
+                writeln!(self.vm.output(), "{}", err)?;
+                return Ok(());
+            }
+        };
+
+        for node in tree.tree.iter() {
+            if node.token.token_type == TokenType::Label {
+                self.defined_labels.insert(node.token.value.clone());
+            }
+        }
+
+        let byte_code = self.linker.link(&byte_code);
+        self.vm.append_code(byte_code);
+        if let Err(err) = self.vm.resume() {
+            // Calls that were running when the error happened will never return. The stack is kept.
+            self.vm.return_stack_mut().clear();
+            writeln!(self.vm.output(), "{}", err)?;
+        }
+
+        self.print_stack()
+    }
+
+    fn print_stack(&mut self) -> io::Result<()> {
+        let stack = format!("{:?}", self.vm.stack());
+        writeln!(self.vm.output(), "stack: {}", stack)
+    }
+
+    fn print_disasm(&mut self) -> io::Result<()> {
+        let mut labels = self.linker.labels().to_vec();
+        for label in labels.iter_mut() {
+            label.name = self.generator.label_name(label.label_num).map(String::from);
+        }
+
+        // Spans are relative to the line each instruction was entered on, so they are not shown.
+        let byte_code: Vec<ByteCode> = self
+            .vm
+            .byte_code()
+            .iter()
+            .map(|op| ByteCode { span: None, ..*op })
+            .collect();
+        let listing = disassemble(&byte_code, &labels, None);
+
+        write!(self.vm.output(), "{}", listing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_repl(input: &str) -> String {
+        let mut output = Vec::new();
+        let vm = ShrekVM::<i32>::with_io(Vec::new(), input.as_bytes(), &mut output);
+        Repl::new(vm).run().unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_stack_kept_between_lines() {
+        let output = run_repl("SRR\nSRRR\n");
+        assert!(output.contains("stack: [2]\n"));
+        assert!(output.contains("stack: [2, 3]\n"));
+    }
+
+    #[test]
+    fn test_multi_line_label() {
+        // The first line jumps to !E!, so nothing runs until !E! is defined on the third line.
+        let output = run_repl("SRRR !R! SRK!E!\nSR SRRRE\nSK!R! !E!\n");
+        assert_eq!(2, output.matches(CONTINUE_PROMPT).count());
+        assert!(output.contains("stack: [0]\n"));
+    }
+
+    #[test]
+    fn test_labels_kept_between_inputs() {
+        // Push -1 and skip over the bump at !R!. The second input jumps back to !R! while the top is negative.
+        let output = run_repl("SR SRRRRRRRRE SK!S! !R! R !S!\nSRRK!R!\n");
+        assert!(output.contains("stack: [-1]\n"));
+        assert!(output.contains("stack: [0]\n"));
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let output = run_repl("SRX\n");
+        assert!(output.contains("error: Invalid Token\n --> <repl>:1:3\n"));
+        assert!(output.contains("1 | SRX\n  |   ^\n"));
+    }
+
+    #[test]
+    fn test_disasm() {
+        let output = run_repl("S SK!R! !R!\n:disasm\n");
+        assert!(output.contains(
+            "   0  Push0      0\n   1  Push0      0\n   2  Jump       -> 3     !R!\n!R!\n"
+        ));
+    }
+
+    #[test]
+    fn test_reset() {
+        let output = run_repl("SRR\n:reset\n:stack\n");
+        assert!(output.contains("stack: []\n"));
+    }
+
+    #[test]
+    fn test_runtime_error_keeps_session() {
+        let output = run_repl("H\nSR\n");
+        assert!(output.contains("cannot pop"));
+        assert!(output.contains("stack: [1]\n"));
+    }
+
+    #[test]
+    fn test_runtime_error_clears_calls() {
+        // The call to !R! fails before it returns, so its return address must not be used by a later return.
+        let input = "SK!E! !R! H !E!\nSRRRK!R!\n";
+        let mut output = Vec::new();
+        let vm = ShrekVM::<i32>::with_io(Vec::new(), input.as_bytes(), &mut output);
+        let mut repl = Repl::new(vm);
+        repl.run().unwrap();
+        assert!(repl.vm.return_stack().is_empty());
+        drop(repl);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("cannot pop"));
+    }
+}
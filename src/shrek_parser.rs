@@ -1,4 +1,5 @@
 use crate::byte_code::{ByteCode, OpCode};
+use crate::span::Span;
 
 use regex::Regex;
 use std::collections::HashMap;
@@ -13,10 +14,10 @@ pub enum TokenType {
     Comment,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct Token {
     pub token_type: TokenType,
-    pub index: usize,
+    pub span: Span,
     pub value: String,
 }
 
@@ -30,78 +31,88 @@ pub struct Tokenizer {
 pub struct SyntaxNode {
     pub token: Token,
     pub children: Vec<SyntaxNode>, // Next pointer?
+    /// Span of the token and all of its children.
+    pub span: Span,
 }
 
 pub struct SyntaxTree {
     pub tree: Vec<SyntaxNode>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SyntaxError {
-    pub index: usize,
+    pub span: Span,
+    pub message: String,
+    /// Another location related to the error.
+    pub note: Option<Note>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Note {
+    pub span: Span,
     pub message: String,
 }
 
 pub type ParseResult<T> = Result<T, SyntaxError>;
 
+/// Generates byte code from syntax trees. Label numbers are kept between calls to `generate`, so a program can be
+/// compiled a piece at a time.
+#[derive(Default)]
+pub struct CodeGenerator {
+    label_map: HashMap<String, i32>,
+}
+
 pub fn generate_byte_code(syntax_tree: &SyntaxTree) -> ParseResult<Vec<ByteCode>> {
-    let mut byte_code = Vec::new();
+    CodeGenerator::new().generate(syntax_tree)
+}
 
-    let mut label_map = HashMap::<&String, i32>::new();
+/// Find jumps to labels that are never defined and labels that are defined more than once. The problems are returned
+/// in source order.
+pub fn check_labels(syntax_tree: &SyntaxTree) -> Vec<SyntaxError> {
+    let mut errors = Vec::new();
+    let mut definitions = HashMap::<&str, Span>::new();
 
     for node in syntax_tree.tree.iter() {
-        match node.token.token_type {
-            TokenType::Label => {
-                // Get or generate a label number for this label.
-                let arg = get_label_num(&mut label_map, &node.token.value);
-                let code = ByteCode {
-                    op_code: OpCode::Label,
-                    arg: arg,
-                };
-                byte_code.push(code);
-            }
-            TokenType::Command => {
-                let op_code = get_op_code(&node.token.value)
-                    .ok_or_else(|| SyntaxError::new(node.token.index, "invalid command"))?;
-
-                let mut code = ByteCode { op_code, arg: 0 };
-
-                // Jumps will use the label's number as the argument.
-                if code.op_code == OpCode::Jump {
-                    // This is assumed to be checked in the parser. Reasserting this assumption here.
-                    if node.children.is_empty()
-                        || node.children[0].token.token_type != TokenType::Label
-                    {
-                        return Err(SyntaxError::new(
-                            node.token.index,
-                            "jump must be followed by a label",
-                        ));
-                    }
-
-                    // Get or generate a label number for this label.
-                    let child_label = &node.children[0].token.value;
-                    code.arg = get_label_num(&mut label_map, child_label);
-                }
+        if node.token.token_type != TokenType::Label {
+            continue;
+        }
 
-                byte_code.push(code);
+        let label = node.token.value.as_str();
+        match definitions.get(label) {
+            Some(first) => {
+                let mut err = SyntaxError::new(
+                    node.span,
+                    &format!("label {} is defined more than once", label),
+                );
+                err.note = Some(Note {
+                    span: *first,
+                    message: format!("{} is first defined here", label),
+                });
+                errors.push(err);
+            }
+            None => {
+                definitions.insert(label, node.span);
             }
-            _ => (), // Evertying else does not get byte code.
         }
     }
 
-    Ok(byte_code)
-}
-
-fn get_label_num<'a>(label_map: &mut HashMap<&'a String, i32>, label: &'a String) -> i32 {
-    let arg = match label_map.get(&label) {
-        Some(x) => *x,
-        None => {
-            let new_val: i32 = label_map.len() as i32;
-            label_map.insert(&label, new_val);
-            new_val
+    // Jump targets are the label children of jump commands.
+    for child in syntax_tree
+        .tree
+        .iter()
+        .flat_map(|node| node.children.iter())
+    {
+        let label = child.token.value.as_str();
+        if child.token.token_type == TokenType::Label && !definitions.contains_key(label) {
+            errors.push(SyntaxError::new(
+                child.span,
+                &format!("jump to undefined label {}", label),
+            ));
         }
-    };
-    arg
+    }
+
+    errors.sort_by_key(|err| err.span.start);
+    errors
 }
 
 fn get_op_code(value: &str) -> Option<OpCode> {
@@ -129,16 +140,35 @@ impl Tokenizer {
         let mut tokens = Vec::new();
 
         let mut index: usize = 0;
+        let mut line: usize = 1;
+        let mut column: usize = 1;
         while index < code.len() {
-            let token = self.next_token(index, code)?;
-            index += token.value.len();
+            let token = self.next_token(index, line, column, code)?;
+
+            // Move the line and column past the token. Whitespace and comments can contain newlines.
+            for c in token.value.chars() {
+                if c == '\n' {
+                    line += 1;
+                    column = 1;
+                } else {
+                    column += 1;
+                }
+            }
+
+            index = token.span.end;
             tokens.push(token);
         }
 
         Ok(tokens)
     }
 
-    fn next_token(&self, index: usize, code: &str) -> ParseResult<Token> {
+    fn next_token(
+        &self,
+        index: usize,
+        line: usize,
+        column: usize,
+        code: &str,
+    ) -> ParseResult<Token> {
         let mtch: regex::Match;
         let token_type: TokenType;
 
@@ -157,19 +187,97 @@ impl Tokenizer {
             token_type = TokenType::Comment;
             mtch = m;
         } else {
-            return Err(SyntaxError::new(index, "Invalid Token"));
+            // Point at the whole character that could not be tokenized.
+            let len = code_slice.chars().next().map_or(0, char::len_utf8);
+            let span = Span::new(index, index + len, line, column);
+            return Err(SyntaxError::new(span, "Invalid Token"));
         };
 
         Ok(Token {
             token_type,
-            index,
+            span: Span::new(index + mtch.start(), index + mtch.end(), line, column),
             value: code_slice[mtch.start()..mtch.end()].to_string(),
         })
     }
 }
 
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Tokenizer::new()
+    }
+}
+
+impl CodeGenerator {
+    pub fn new() -> CodeGenerator {
+        CodeGenerator {
+            label_map: HashMap::new(),
+        }
+    }
+
+    pub fn generate(&mut self, syntax_tree: &SyntaxTree) -> ParseResult<Vec<ByteCode>> {
+        let mut byte_code = Vec::new();
+
+        for node in syntax_tree.tree.iter() {
+            match node.token.token_type {
+                TokenType::Label => {
+                    // Get or generate a label number for this label.
+                    let arg = self.get_label_num(&node.token.value);
+                    byte_code.push(ByteCode::with_span(OpCode::Label, arg, node.span));
+                }
+                TokenType::Command => {
+                    let op_code = get_op_code(&node.token.value)
+                        .ok_or_else(|| SyntaxError::new(node.span, "invalid command"))?;
+
+                    let mut code = ByteCode::with_span(op_code, 0, node.span);
+
+                    // Jumps will use the label's number as the argument.
+                    if code.op_code == OpCode::Jump {
+                        // This is assumed to be checked in the parser. Reasserting this assumption here.
+                        if node.children.is_empty()
+                            || node.children[0].token.token_type != TokenType::Label
+                        {
+                            return Err(SyntaxError::new(
+                                node.span,
+                                "jump must be followed by a label",
+                            ));
+                        }
+
+                        // Get or generate a label number for this label.
+                        let child_label = &node.children[0].token.value;
+                        code.arg = self.get_label_num(child_label);
+                    }
+
+                    byte_code.push(code);
+                }
+                _ => (), // Evertying else does not get byte code.
+            }
+        }
+
+        Ok(byte_code)
+    }
+
+    /// Get the label text (including the `!` characters) assigned to a label number.
+    pub fn label_name(&self, label_num: i32) -> Option<&str> {
+        self.label_map
+            .iter()
+            .find(|(_, num)| **num == label_num)
+            .map(|(name, _)| name.as_str())
+    }
+
+    fn get_label_num(&mut self, label: &str) -> i32 {
+        match self.label_map.get(label) {
+            Some(x) => *x,
+            None => {
+                let new_val: i32 = self.label_map.len() as i32;
+                self.label_map.insert(label.to_string(), new_val);
+                new_val
+            }
+        }
+    }
+}
+
 impl SyntaxTree {
-    pub fn generate(tokens: &Vec<Token>) -> ParseResult<SyntaxTree> {
+    pub fn generate(tokens: &[Token]) -> ParseResult<SyntaxTree> {
         let mut tree = SyntaxTree { tree: Vec::new() };
 
         let mut index = 0;
@@ -193,7 +301,7 @@ impl SyntaxTree {
         Ok(tree)
     }
 
-    fn parse_command(tokens: &Vec<Token>, index: &mut usize) -> ParseResult<SyntaxNode> {
+    fn parse_command(tokens: &[Token], index: &mut usize) -> ParseResult<SyntaxNode> {
         // Assumes that index range check was done in caller.
         debug_assert!(*index < tokens.len());
 
@@ -201,11 +309,12 @@ impl SyntaxTree {
         *index += 1;
 
         let op_code = get_op_code(&token.value)
-            .ok_or_else(|| SyntaxError::new(token.index, "invalid operation code"))?;
+            .ok_or_else(|| SyntaxError::new(token.span, "invalid operation code"))?;
 
         let mut node = SyntaxNode {
             token: token.clone(),
             children: Vec::new(),
+            span: token.span,
         };
 
         // Jumps must be followed by a label. Enforce that rule here.
@@ -214,19 +323,21 @@ impl SyntaxTree {
                 // Inspect the next node and ensure it is a label. Make it a child of the node being processed.
                 let next_token = &tokens[*index];
                 if let TokenType::Label = next_token.token_type {
-                    node.children.push(SyntaxTree::parse_label(tokens, index)?);
+                    let label = SyntaxTree::parse_label(tokens, index)?;
+                    node.span = node.span.to(label.span);
+                    node.children.push(label);
                 } else {
-                    return Err(SyntaxError::new(token.index, "missing label after jump"));
+                    return Err(SyntaxError::new(token.span, "missing label after jump"));
                 }
             } else {
-                return Err(SyntaxError::new(token.index, "missing command after jump"));
+                return Err(SyntaxError::new(token.span, "missing command after jump"));
             };
         };
 
         Ok(node)
     }
 
-    fn parse_label(tokens: &Vec<Token>, index: &mut usize) -> ParseResult<SyntaxNode> {
+    fn parse_label(tokens: &[Token], index: &mut usize) -> ParseResult<SyntaxNode> {
         // Assumes that index range check was done in caller.
         debug_assert!(*index < tokens.len());
 
@@ -236,22 +347,120 @@ impl SyntaxTree {
         let node = SyntaxNode {
             token: token.clone(),
             children: Vec::new(),
+            span: token.span,
         };
         Ok(node)
     }
 }
 
 impl SyntaxError {
-    fn new(index: usize, message: &str) -> SyntaxError {
+    fn new(span: Span, message: &str) -> SyntaxError {
         SyntaxError {
-            index,
+            span,
             message: message.to_string(),
+            note: None,
+        }
+    }
+
+    /// Render the error with the offending source line, in the style of rustc.
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        self.render_level("error", file_name, source)
+    }
+
+    /// Render the error as a warning.
+    pub fn render_warning(&self, file_name: &str, source: &str) -> String {
+        self.render_level("warning", file_name, source)
+    }
+
+    fn render_level(&self, level: &str, file_name: &str, source: &str) -> String {
+        let mut text = self.span.render(level, &self.message, file_name, source);
+        if let Some(note) = &self.note {
+            text += "\n";
+            text += &note.span.render("note", &note.message, file_name, source);
         }
+        text
     }
 }
 
 impl fmt::Display for SyntaxError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Syntax Error at index {}: {}", self.index, self.message)
+        write!(
+            f,
+            "Syntax Error at line {}, column {}: {}",
+            self.span.line, self.span.column, self.message
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(code: &str) -> SyntaxTree {
+        let tokens = Tokenizer::new().tokenize(code).unwrap();
+        SyntaxTree::generate(&tokens).unwrap()
+    }
+
+    #[test]
+    fn test_check_labels() {
+        let tree = parse("!S! SK!S! SK!H!");
+        assert!(check_labels(&parse("!S! SK!S! SK!H! !H!")).is_empty());
+
+        let errors = check_labels(&tree);
+        assert_eq!(1, errors.len());
+        assert_eq!("jump to undefined label !H!", errors[0].message);
+        assert_eq!(Span::new(12, 15, 1, 13), errors[0].span);
+    }
+
+    #[test]
+    fn test_check_duplicate_labels() {
+        let errors = check_labels(&parse("!S!\nSK!S!\n!S!"));
+        assert_eq!(1, errors.len());
+        assert_eq!("label !S! is defined more than once", errors[0].message);
+        assert_eq!(Span::new(10, 13, 3, 1), errors[0].span);
+
+        let note = errors[0].note.as_ref().unwrap();
+        assert_eq!(Span::new(0, 3, 1, 1), note.span);
+    }
+
+    #[test]
+    fn test_token_spans() {
+        let tokens = Tokenizer::new().tokenize("SR # push 1\n  SK!E!").unwrap();
+
+        let commands: Vec<&Token> = tokens
+            .iter()
+            .filter(|t| t.token_type == TokenType::Command)
+            .collect();
+        assert_eq!(Span::new(0, 1, 1, 1), commands[0].span);
+        assert_eq!(Span::new(1, 2, 1, 2), commands[1].span);
+        assert_eq!(Span::new(14, 15, 2, 3), commands[2].span);
+        assert_eq!(Span::new(15, 16, 2, 4), commands[3].span);
+
+        let label = tokens.last().unwrap();
+        assert_eq!(TokenType::Label, label.token_type);
+        assert_eq!(Span::new(16, 19, 2, 5), label.span);
+    }
+
+    #[test]
+    fn test_jump_node_span() {
+        let tokens = Tokenizer::new().tokenize("S\nSK!E!").unwrap();
+        let tree = SyntaxTree::generate(&tokens).unwrap();
+
+        let jump = &tree.tree[2];
+        assert_eq!(Span::new(3, 7, 2, 2), jump.span);
+        assert_eq!(Span::new(4, 7, 2, 3), jump.children[0].span);
+    }
+
+    #[test]
+    fn test_invalid_token_span() {
+        let err = Tokenizer::new().tokenize("SR\n  Sé").unwrap_err();
+        assert_eq!(Span::new(6, 8, 2, 4), err.span);
+    }
+
+    #[test]
+    fn test_missing_label_span() {
+        let tokens = Tokenizer::new().tokenize("S\n SK").unwrap();
+        let err = SyntaxTree::generate(&tokens).err().unwrap();
+        assert_eq!(Span::new(4, 5, 2, 3), err.span);
     }
 }
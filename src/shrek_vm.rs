@@ -1,50 +1,347 @@
+use crate::arithmetic::{ArithmeticError, ArithmeticMode};
 use crate::builtins;
 use crate::byte_code::{ByteCode, OpCode};
+use crate::cell::CellValue;
+use crate::limits::{Limit, LimitedWriter, Limits};
 use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::marker::PhantomData;
+use std::time::Instant;
 use std::vec::Vec;
 
-pub struct ShrekVM {
+/// A function provided by the embedding program that can be called with the func command.
+pub type HostFunction<'io, C = i32> = Box<dyn FnMut(&mut ShrekVM<C>) -> VmResult<()> + 'io>;
+
+struct HostFunctionEntry<'io, C> {
+    arity: usize,
+    func: HostFunction<'io, C>,
+}
+
+/// Executes linked byte code (see the `linker` module). Jump arguments are the address to continue at.
+///
+/// The values on the stack are `C`, which defaults to `i32`. See the `cell` module for the other supported types.
+pub struct ShrekVM<'io, C = i32> {
     byte_code: Vec<ByteCode>,
 
     program_counter: usize,
-    stack: Vec<i32>,
+    stack: Vec<C>,
+    /// Addresses to continue at when the calls that have not returned yet return, with the most recent call last.
+    return_stack: Vec<usize>,
+    /// Memory for the load and store builtins. It starts empty and is grown by the program.
+    memory: Vec<C>,
+    /// Global variable slots for the get and set builtins.
+    globals: Vec<C>,
+
+    input: Box<dyn BufRead + 'io>,
+    output: LimitedWriter<'io>,
+
+    host_functions: HashMap<i32, HostFunctionEntry<'io, C>>,
+
+    arithmetic_mode: ArithmeticMode,
+    prompt: bool,
+
+    limits: Limits,
+    instruction_count: u64,
+}
+
+/// Creates a `ShrekVM` with I/O streams and settings other than the defaults.
+///
+/// ```
+/// use shrek_lang_rust::arithmetic::ArithmeticMode;
+/// use shrek_lang_rust::shrek_vm::ShrekVM;
+///
+/// let program = shrek_lang_rust::compile("SE SRE").unwrap();
+///
+/// let mut output = Vec::new();
+/// let mut vm = ShrekVM::<i32>::builder(program.byte_code().to_vec())
+///     .input(&b"hi\n"[..])
+///     .output(&mut output)
+///     .arithmetic_mode(ArithmeticMode::Wrapping)
+///     .prompt(false)
+///     .build();
+/// vm.run().unwrap();
+/// drop(vm);
+///
+/// assert_eq!(b"104\n", &output[..]);
+/// ```
+pub struct ShrekVMBuilder<'io, C = i32> {
+    byte_code: Vec<ByteCode>,
+    input: Option<Box<dyn BufRead + 'io>>,
+    output: Option<Box<dyn Write + 'io>>,
+    arithmetic_mode: ArithmeticMode,
+    prompt: bool,
+    limits: Limits,
+    _cell: PhantomData<C>,
+}
+
+impl<'io, C: CellValue> ShrekVMBuilder<'io, C> {
+    /// The stream that input builtins read from. Defaults to stdin.
+    pub fn input<R: BufRead + 'io>(mut self, input: R) -> Self {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// The stream that output builtins write to. Defaults to stdout.
+    pub fn output<W: Write + 'io>(mut self, output: W) -> Self {
+        self.output = Some(Box::new(output));
+        self
+    }
+
+    pub fn arithmetic_mode(mut self, mode: ArithmeticMode) -> Self {
+        self.arithmetic_mode = mode;
+        self
+    }
+
+    /// Whether the input builtin writes `input: ` before reading. Defaults to true.
+    pub fn prompt(mut self, prompt: bool) -> Self {
+        self.prompt = prompt;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
 
-    jump_table: HashMap<i32, usize>,
+    pub fn build(self) -> ShrekVM<'io, C> {
+        // Stdin is locked for each read instead of for the life of the VM, so other VMs can share it. A one byte
+        // buffer never reads ahead, so nothing is taken from stdin that another reader would miss.
+        let input = self
+            .input
+            .unwrap_or_else(|| Box::new(BufReader::with_capacity(1, io::stdin())));
+        let output = self.output.unwrap_or_else(|| Box::new(io::stdout()));
+
+        let mut vm = ShrekVM {
+            byte_code: self.byte_code,
+            program_counter: 0,
+            stack: Vec::new(),
+            return_stack: Vec::new(),
+            memory: Vec::new(),
+            globals: vec![C::default(); GLOBAL_SLOTS],
+            input,
+            output: LimitedWriter::new(output),
+            host_functions: HashMap::new(),
+            arithmetic_mode: self.arithmetic_mode,
+            prompt: self.prompt,
+            limits: Limits::default(),
+            instruction_count: 0,
+        };
+        vm.set_limits(self.limits);
+        vm
+    }
 }
 
+/// Number of global variable slots. Every slot starts at 0.
+pub const GLOBAL_SLOTS: usize = 64;
+
+/// Number of stack items, from the top, captured when a runtime error happens.
+pub const TRACE_STACK_DEPTH: usize = 8;
+
+/// Number of instructions executed between checks of the deadline.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
 #[derive(Debug, Clone)]
 pub struct ShrekRuntimeError {
+    /// What went wrong, for callers that handle specific errors.
+    pub kind: ErrorKind,
+    /// A description of the error for people, which can have more detail than the kind.
     pub message: String,
+    /// Where the error happened. Set when the error is returned from `run` or `resume`.
+    pub trace: Option<Box<ErrorTrace>>,
+}
+
+/// The kind of a runtime error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An instruction or function needed more items than were on the stack.
+    StackUnderflow {
+        needed: usize,
+        had: usize,
+    },
+    /// A jump was given a type other than 0 through 4.
+    InvalidJumpType(i32),
+    /// The func command was given a number with no builtin or host function.
+    UnknownFunction(i32),
+    /// A jump to a label that is not defined.
+    LabelNotFound,
+    /// A return with no call to return from.
+    ReturnWithoutCall,
+    /// A load or store outside of the VM's memory. The address is clamped to fit in an `i32`.
+    MemoryOutOfBounds {
+        address: i32,
+        size: usize,
+    },
+    DivisionByZero,
+    /// An arithmetic result did not fit in the cell type, in `ArithmeticMode::Checked`.
+    Overflow,
+    /// A limit set with `ShrekVM::set_limits` was exceeded.
+    LimitExceeded(Limit),
+    /// Reading input or writing output failed.
+    Io,
+    /// Input could not be parsed, such as a line that is not a number.
+    InvalidInput,
+    /// A builtin was given an argument outside the range it accepts, such as a negative count.
+    InvalidArgument,
+    /// A host function could not be registered because its number is reserved for builtins or already used.
+    FunctionNumberInUse(i32),
+    /// An error returned by a host function or native plugin.
+    Host,
+}
+
+/// The state of the VM when a runtime error happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorTrace {
+    pub program_counter: usize,
+    /// The instruction that failed. Its span points at the source the instruction was generated from.
+    pub instruction: ByteCode,
+    /// Up to `TRACE_STACK_DEPTH` items from the top of the stack, with the top of the stack first. The values are
+    /// formatted so the trace does not depend on the VM's cell type.
+    pub stack_top: Vec<String>,
+    /// Total number of items on the stack.
+    pub stack_size: usize,
 }
 
 pub type VmResult<T> = Result<T, ShrekRuntimeError>;
 
-impl ShrekVM {
-    pub fn new(byte_code: Vec<ByteCode>) -> ShrekVM {
-        ShrekVM {
+impl<'io, C: CellValue> ShrekVM<'io, C> {
+    /// Create a VM that reads from stdin and writes to stdout.
+    pub fn new(byte_code: Vec<ByteCode>) -> ShrekVM<'io, C> {
+        ShrekVM::builder(byte_code).build()
+    }
+
+    /// Create a VM that reads input from `input` and writes output to `output`.
+    pub fn with_io<R, W>(byte_code: Vec<ByteCode>, input: R, output: W) -> ShrekVM<'io, C>
+    where
+        R: BufRead + 'io,
+        W: Write + 'io,
+    {
+        ShrekVM::builder(byte_code)
+            .input(input)
+            .output(output)
+            .build()
+    }
+
+    /// Start building a VM that will execute `byte_code`. See [`ShrekVMBuilder`].
+    pub fn builder(byte_code: Vec<ByteCode>) -> ShrekVMBuilder<'io, C> {
+        ShrekVMBuilder {
             byte_code,
-            program_counter: 0,
-            stack: Vec::new(),
-            jump_table: HashMap::new(),
+            input: None,
+            output: None,
+            arithmetic_mode: ArithmeticMode::default(),
+            prompt: true,
+            limits: Limits::default(),
+            _cell: PhantomData,
+        }
+    }
+
+    /// Add linked code to the end of the program. The next call to `run` or `resume` starts executing at the
+    /// beginning of the added code. Jumps in the added code must already be resolved to addresses in the whole
+    /// program, for example by linking each piece of code with the same `Linker`.
+    pub fn append_code(&mut self, byte_code: Vec<ByteCode>) {
+        self.program_counter = self.byte_code.len();
+        self.byte_code.extend(byte_code);
+    }
+
+    /// Remove all code, clear the stack, return stack, memory and globals, and restart the counts used for limits.
+    /// I/O streams, host functions and settings are kept.
+    pub fn reset(&mut self) {
+        self.byte_code.clear();
+        self.program_counter = 0;
+        self.stack.clear();
+        self.return_stack.clear();
+        self.memory.clear();
+        self.globals.iter_mut().for_each(|x| *x = C::default());
+        self.instruction_count = 0;
+        self.output.written = 0;
+    }
+
+    /// Register a host function that scripts can call with the func command using `func_num`. The VM checks that
+    /// at least `arity` items are on the stack before calling `func`.
+    ///
+    /// `func_num` must be above `builtins::BUILTIN_MAX` and must not already be registered.
+    pub fn register_function<F>(&mut self, func_num: i32, arity: usize, func: F) -> VmResult<()>
+    where
+        F: FnMut(&mut ShrekVM<C>) -> VmResult<()> + 'io,
+    {
+        if func_num <= builtins::BUILTIN_MAX {
+            return Err(ShrekRuntimeError::with_kind(
+                ErrorKind::FunctionNumberInUse(func_num),
+                "function number is reserved for builtins",
+            ));
         }
+
+        if self.host_functions.contains_key(&func_num) {
+            return Err(ShrekRuntimeError::with_kind(
+                ErrorKind::FunctionNumberInUse(func_num),
+                "function number already registered",
+            ));
+        }
+
+        let entry = HostFunctionEntry {
+            arity,
+            func: Box::new(func),
+        };
+        self.host_functions.insert(func_num, entry);
+        Ok(())
+    }
+
+    /// How the arithmetic builtins handle overflow. Defaults to `ArithmeticMode::Checked`.
+    pub fn arithmetic_mode(&self) -> ArithmeticMode {
+        self.arithmetic_mode
     }
 
-    pub fn push(&mut self, value: i32) {
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic_mode = mode;
+    }
+
+    /// Whether the input builtin writes `input: ` to the output stream before reading a line.
+    pub fn prompt(&self) -> bool {
+        self.prompt
+    }
+
+    pub fn set_prompt(&mut self, prompt: bool) {
+        self.prompt = prompt;
+    }
+
+    /// The resource limits. No limits are set by default.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Set the resource limits. Exceeding one stops the program with an `ErrorKind::LimitExceeded` error.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.output.max = limits.max_output_bytes;
+    }
+
+    /// Number of instructions executed since the VM was created or last reset.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    pub fn push(&mut self, value: C) {
         self.stack.push(value);
     }
 
-    pub fn pop(&mut self) -> VmResult<i32> {
+    pub fn pop(&mut self) -> VmResult<C> {
         match self.stack.pop() {
             Some(x) => Ok(x),
-            None => Err(ShrekRuntimeError::new("cannot pop: stack is empty")),
+            None => Err(ShrekRuntimeError::with_kind(
+                ErrorKind::StackUnderflow { needed: 1, had: 0 },
+                "cannot pop: stack is empty",
+            )),
         }
     }
 
-    pub fn peek(&self) -> VmResult<i32> {
+    pub fn peek(&self) -> VmResult<C> {
         match self.stack.last() {
-            Some(x) => Ok(*x),
-            None => Err(ShrekRuntimeError::new("cannot peek: stack is empty")),
+            Some(x) => Ok(x.clone()),
+            None => Err(ShrekRuntimeError::with_kind(
+                ErrorKind::StackUnderflow { needed: 1, had: 0 },
+                "cannot peek: stack is empty",
+            )),
         }
     }
 
@@ -52,28 +349,141 @@ impl ShrekVM {
         self.stack.len()
     }
 
-    pub fn run(&mut self) -> VmResult<i32> {
-        self.build_jump_table();
+    /// The stack, with the top of the stack as the last item.
+    pub fn stack(&self) -> &[C] {
+        &self.stack
+    }
+
+    /// The stack, for host functions that need to rearrange it. The top of the stack is the last item.
+    pub fn stack_mut(&mut self) -> &mut Vec<C> {
+        &mut self.stack
+    }
+
+    /// Return addresses of the calls that have not returned yet, with the most recent call last.
+    pub fn return_stack(&self) -> &[usize] {
+        &self.return_stack
+    }
+
+    /// The return addresses, for clearing calls that will never return, such as after a runtime error.
+    pub fn return_stack_mut(&mut self) -> &mut Vec<usize> {
+        &mut self.return_stack
+    }
+
+    /// The memory used by the load and store builtins.
+    pub fn memory(&self) -> &[C] {
+        &self.memory
+    }
+
+    /// The memory, for host functions that share data with the program. Growing it directly is not checked against
+    /// the memory limit.
+    pub fn memory_mut(&mut self) -> &mut Vec<C> {
+        &mut self.memory
+    }
+
+    /// The global variable slots used by the get and set builtins.
+    pub fn globals(&self) -> &[C] {
+        &self.globals
+    }
+
+    pub fn globals_mut(&mut self) -> &mut [C] {
+        &mut self.globals
+    }
+
+    /// All code loaded into the VM.
+    pub fn byte_code(&self) -> &[ByteCode] {
+        &self.byte_code
+    }
+
+    /// The stream that input builtins read from.
+    pub fn input(&mut self) -> &mut dyn BufRead {
+        self.input.as_mut()
+    }
 
+    /// The stream that output builtins write to.
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut self.output
+    }
+
+    /// Run the program to completion. The top of the stack is popped and returned as the exit code.
+    pub fn run(&mut self) -> VmResult<C> {
+        self.resume()?;
+
+        let exit_code = self.stack.pop().unwrap_or_default();
+
+        Ok(exit_code)
+    }
+
+    /// Run until the program counter leaves the code, leaving the stack as it is.
+    pub fn resume(&mut self) -> VmResult<()> {
         while self.program_counter < self.byte_code.len() {
-            self.step()?;
+            let program_counter = self.program_counter;
+            if let Err(mut err) = self.step_limited() {
+                err.trace = Some(Box::new(self.trace(program_counter)));
+                return Err(err);
+            }
         }
 
-        let exit_code = match self.stack.pop() {
-            Some(x) => x,
-            None => 0,
-        };
+        // Make sure everything written by the program is visible before returning to the caller.
+        if self.output.flush().is_err() {
+            return Err(ShrekRuntimeError::with_kind(
+                ErrorKind::Io,
+                "i/o error writing output",
+            ));
+        }
 
-        Ok(exit_code)
+        Ok(())
     }
 
-    fn build_jump_table(&mut self) {
-        for i in 0..self.byte_code.len() {
-            let op = &self.byte_code[i];
-            if op.op_code == OpCode::Label {
-                self.jump_table.insert(op.arg, i);
+    fn trace(&self, program_counter: usize) -> ErrorTrace {
+        ErrorTrace {
+            program_counter,
+            instruction: self.byte_code[program_counter],
+            stack_top: self
+                .stack
+                .iter()
+                .rev()
+                .take(TRACE_STACK_DEPTH)
+                .map(|v| v.to_string())
+                .collect(),
+            stack_size: self.stack.len(),
+        }
+    }
+
+    /// Execute one instruction, checking the limits before and after it.
+    fn step_limited(&mut self) -> VmResult<()> {
+        if let Some(max) = self.limits.max_instructions {
+            if self.instruction_count >= max {
+                return Err(ErrorKind::LimitExceeded(Limit::Instructions).into());
             }
         }
+
+        if let Some(deadline) = self.limits.deadline {
+            if self
+                .instruction_count
+                .is_multiple_of(DEADLINE_CHECK_INTERVAL)
+                && Instant::now() >= deadline
+            {
+                return Err(ErrorKind::LimitExceeded(Limit::Deadline).into());
+            }
+        }
+
+        self.instruction_count += 1;
+        let result = self.step();
+
+        // A write refused by the output limit shows up as an i/o error from the builtin. Report the limit instead.
+        if self.output.exceeded {
+            self.output.exceeded = false;
+            return Err(ErrorKind::LimitExceeded(Limit::OutputBytes).into());
+        }
+        result?;
+
+        if let Some(max) = self.limits.max_stack_depth {
+            if self.stack.len() > max {
+                return Err(ErrorKind::LimitExceeded(Limit::StackDepth).into());
+            }
+        }
+
+        Ok(())
     }
 
     fn step(&mut self) -> VmResult<()> {
@@ -83,7 +493,7 @@ impl ShrekVM {
         }
 
         match self.byte_code[self.program_counter].op_code {
-            OpCode::Label => {
+            OpCode::Label | OpCode::NoOp => {
                 self.step_code();
             }
             OpCode::Push0 => self.op_push0()?,
@@ -92,7 +502,6 @@ impl ShrekVM {
             OpCode::Func => self.op_func()?,
             OpCode::Jump => self.op_jump()?,
             OpCode::PushConst => self.op_push_const()?,
-            _ => (),
         }
 
         Ok(())
@@ -103,7 +512,7 @@ impl ShrekVM {
     }
 
     fn op_push0(&mut self) -> VmResult<()> {
-        self.push(0);
+        self.push(C::default());
         self.step_code();
         Ok(())
     }
@@ -115,26 +524,60 @@ impl ShrekVM {
     }
 
     fn op_bump(&mut self) -> VmResult<()> {
-        let mut v = self.pop()?;
-        v += 1;
+        let v = self.pop()?;
+        let v = match self.arithmetic_mode.add(v, C::from_i32(1)) {
+            Ok(x) => x,
+            Err(err) => return Err(ShrekRuntimeError::arithmetic(err, "bump")),
+        };
         self.push(v);
         self.step_code();
         Ok(())
     }
 
     fn op_func(&mut self) -> VmResult<()> {
-        let func_num = self.pop()?;
-        builtins::execute_builtin(self, func_num)?;
+        // Values that do not fit in an i32 are clamped, which never names a function.
+        let func_num = self.pop()?.to_i32_saturating();
+        if func_num > builtins::BUILTIN_MAX {
+            self.call_host_function(func_num)?;
+        } else {
+            builtins::execute_builtin(self, func_num)?;
+        }
         self.step_code();
         Ok(())
     }
 
+    fn call_host_function(&mut self, func_num: i32) -> VmResult<()> {
+        // The function is taken out of the registry while it runs so it can be given mutable access to the VM.
+        let mut entry = match self.host_functions.remove(&func_num) {
+            Some(x) => x,
+            None => return Err(ErrorKind::UnknownFunction(func_num).into()),
+        };
+
+        let result = if self.count() < entry.arity {
+            Err(ShrekRuntimeError::with_kind(
+                ErrorKind::StackUnderflow {
+                    needed: entry.arity,
+                    had: self.count(),
+                },
+                &format!(
+                    "host function {} requires {} items on the stack",
+                    func_num, entry.arity
+                ),
+            ))
+        } else {
+            (entry.func)(self)
+        };
+
+        self.host_functions.insert(func_num, entry);
+        result
+    }
+
     fn op_jump(&mut self) -> VmResult<()> {
         // Assumes this function will not be called when program counter beyond code.
         debug_assert!(self.program_counter < self.byte_code.len());
 
         // Pop the top of the stack to get the jump type to do.
-        let jump_type = self.pop()?;
+        let jump_type = self.pop()?.to_i32_saturating();
 
         // Based on the jump type, determine if the jump should happen.
         let should_jump = match jump_type {
@@ -145,15 +588,35 @@ impl ShrekVM {
             1 => {
                 // Jump if 0
                 let s1 = self.peek()?;
-                s1 == 0
+                s1.is_zero()
             }
             2 => {
                 // Jump if negative
                 let s1 = self.peek()?;
-                s1 < 0
+                s1.is_negative()
+            }
+            3 => {
+                // Call, returning to the instruction after this one
+                if let Some(max) = self.limits.max_call_depth {
+                    if self.return_stack.len() >= max {
+                        return Err(ErrorKind::LimitExceeded(Limit::CallDepth).into());
+                    }
+                }
+                self.return_stack.push(self.program_counter + 1);
+                true
+            }
+            4 => {
+                // Return. The label is not used.
+                return match self.return_stack.pop() {
+                    Some(address) => {
+                        self.program_counter = address;
+                        Ok(())
+                    }
+                    None => Err(ErrorKind::ReturnWithoutCall.into()),
+                };
             }
             _ => {
-                return Err(ShrekRuntimeError::new("invalid jump type"));
+                return Err(ErrorKind::InvalidJumpType(jump_type).into());
             }
         };
 
@@ -163,34 +626,79 @@ impl ShrekVM {
             return Ok(());
         }
 
-        // Get the label to jump to from the label map. The code's argument will hold the label number.
-        let label_num = self.byte_code[self.program_counter].arg;
-        match self.jump_table.get(&label_num) {
-            Some(x) => {
-                // Move program counter 1 past the label to save an opeartion.
-                self.program_counter = (*x as usize) + 1;
-                Ok(())
-            }
-            None => Err(ShrekRuntimeError::new("jump label not found in jump map")),
+        // The linker replaced the label number in the code's argument with the address to jump to. A negative
+        // address means the label was never defined.
+        let address = self.byte_code[self.program_counter].arg;
+        if address < 0 {
+            return Err(ErrorKind::LabelNotFound.into());
         }
+
+        self.program_counter = address as usize;
+        Ok(())
     }
 
     fn op_push_const(&mut self) -> VmResult<()> {
         // Assumes this function will not be called when program counter beyond code.
         debug_assert!(self.program_counter < self.byte_code.len());
 
-        self.push(self.byte_code[self.program_counter].arg);
+        self.push(C::from_i32(self.byte_code[self.program_counter].arg));
         self.step_code();
         Ok(())
     }
 }
 
 impl ShrekRuntimeError {
+    /// Create an error for a host function to return. The error's kind is `ErrorKind::Host`.
     pub fn new(message: &str) -> ShrekRuntimeError {
+        ShrekRuntimeError::with_kind(ErrorKind::Host, message)
+    }
+
+    pub fn with_kind(kind: ErrorKind, message: &str) -> ShrekRuntimeError {
         ShrekRuntimeError {
+            kind,
             message: message.to_string(),
+            trace: None,
         }
     }
+
+    /// Create the error for a failed arithmetic operation in the builtin or instruction called `name`.
+    pub(crate) fn arithmetic(err: ArithmeticError, name: &str) -> ShrekRuntimeError {
+        let kind = match err {
+            ArithmeticError::Overflow => ErrorKind::Overflow,
+            ArithmeticError::DivisionByZero => ErrorKind::DivisionByZero,
+        };
+        ShrekRuntimeError::with_kind(kind, &format!("{} in {}", err, name))
+    }
+
+    /// Render the error with the source location of the failing instruction and the top of the stack.
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        let trace = match &self.trace {
+            Some(x) => x,
+            None => return format!("error: {}", self.message),
+        };
+
+        let mut text = match trace.instruction.span {
+            Some(span) => span.render("error", &self.message, file_name, source),
+            None => format!("error: {}", self.message),
+        };
+
+        let instruction = &trace.instruction;
+        text += &format!(
+            "\n  = instruction {}: {:?} {}",
+            trace.program_counter, instruction.op_code, instruction.arg
+        );
+
+        let mut stack: Vec<String> = trace.stack_top.iter().map(|v| v.to_string()).collect();
+        if trace.stack_size > trace.stack_top.len() {
+            stack.push(format!(
+                "... {} more",
+                trace.stack_size - trace.stack_top.len()
+            ));
+        }
+        text += &format!("\n  = stack (top first): [{}]", stack.join(", "));
+
+        text
+    }
 }
 
 impl fmt::Display for ShrekRuntimeError {
@@ -198,3 +706,356 @@ impl fmt::Display for ShrekRuntimeError {
         write!(f, "Runtime Error: {}", self.message)
     }
 }
+
+impl Error for ShrekRuntimeError {}
+
+impl From<ErrorKind> for ShrekRuntimeError {
+    fn from(kind: ErrorKind) -> ShrekRuntimeError {
+        ShrekRuntimeError::with_kind(kind, &kind.to_string())
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::StackUnderflow { needed, had } => write!(
+                f,
+                "stack underflow: {} items needed, {} on the stack",
+                needed, had
+            ),
+            ErrorKind::InvalidJumpType(jump_type) => write!(f, "invalid jump type {}", jump_type),
+            ErrorKind::UnknownFunction(func_num) => write!(f, "unknown function {}", func_num),
+            ErrorKind::LabelNotFound => write!(f, "jump label not found"),
+            ErrorKind::ReturnWithoutCall => write!(f, "return without a call"),
+            ErrorKind::MemoryOutOfBounds { address, size } => write!(
+                f,
+                "memory address {} out of bounds for memory of size {}",
+                address, size
+            ),
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ErrorKind::Overflow => write!(f, "integer overflow"),
+            ErrorKind::LimitExceeded(limit) => write!(f, "{}", limit),
+            ErrorKind::Io => write!(f, "i/o error"),
+            ErrorKind::InvalidInput => write!(f, "invalid input"),
+            ErrorKind::InvalidArgument => write!(f, "invalid argument"),
+            ErrorKind::FunctionNumberInUse(func_num) => {
+                write!(f, "function number {} is already in use", func_num)
+            }
+            ErrorKind::Host => write!(f, "host function error"),
+        }
+    }
+}
+
+impl Error for ErrorKind {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::Span;
+    use std::cell::Cell;
+    use std::io;
+
+    fn push_const(arg: i32) -> ByteCode {
+        ByteCode::new(OpCode::PushConst, arg)
+    }
+
+    fn func() -> ByteCode {
+        ByteCode::new(OpCode::Func, 0)
+    }
+
+    #[test]
+    fn test_stdin_is_shared() {
+        // Two VMs reading stdin can exist at once, since neither holds the stdin lock.
+        let mut first = ShrekVM::<i32>::new(vec![push_const(1)]);
+        let mut second = ShrekVM::<i32>::new(vec![push_const(2)]);
+        assert_eq!(1, first.run().unwrap());
+        assert_eq!(2, second.run().unwrap());
+    }
+
+    #[test]
+    fn test_no_op() {
+        let code = vec![ByteCode::new(OpCode::NoOp, 0), push_const(7)];
+        let mut vm = ShrekVM::<i32>::with_io(code, io::empty(), io::sink());
+        assert_eq!(7, vm.run().unwrap());
+    }
+
+    #[test]
+    fn test_append_code() {
+        // Push -2, then jump over the bump at address 3.
+        let mut vm = ShrekVM::<i32>::with_io(
+            vec![
+                push_const(-2),
+                push_const(0),
+                ByteCode::new(OpCode::Jump, 4),
+                ByteCode::new(OpCode::Bump, 0),
+            ],
+            io::empty(),
+            io::sink(),
+        );
+        vm.resume().unwrap();
+        assert_eq!(&[-2], vm.stack());
+
+        // Jump back to the bump in the earlier code while the top of the stack is negative.
+        vm.append_code(vec![push_const(2), ByteCode::new(OpCode::Jump, 3)]);
+        vm.resume().unwrap();
+        assert_eq!(&[0], vm.stack());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut vm = ShrekVM::<i32>::with_io(vec![push_const(3)], io::empty(), io::sink());
+        vm.resume().unwrap();
+        vm.reset();
+
+        assert_eq!(0, vm.count());
+        assert!(vm.byte_code().is_empty());
+    }
+
+    #[test]
+    fn test_error_trace() {
+        let span = Span::new(4, 5, 2, 1);
+        let byte_code = vec![
+            push_const(1),
+            push_const(2),
+            push_const(5),
+            ByteCode::with_span(OpCode::Jump, 0, span),
+        ];
+        let mut vm = ShrekVM::<i32>::with_io(byte_code, io::empty(), io::sink());
+
+        let err = vm.run().unwrap_err();
+        let trace = err.trace.unwrap();
+        assert_eq!(3, trace.program_counter);
+        assert_eq!(Some(span), trace.instruction.span);
+        assert_eq!(vec!["2", "1"], trace.stack_top);
+        assert_eq!(2, trace.stack_size);
+    }
+
+    #[test]
+    fn test_render_error() {
+        let byte_code = vec![
+            push_const(1),
+            ByteCode::with_span(OpCode::Pop, 0, Span::new(4, 5, 2, 1)),
+            ByteCode::with_span(OpCode::Pop, 0, Span::new(5, 6, 2, 2)),
+        ];
+        let mut vm = ShrekVM::<i32>::with_io(byte_code, io::empty(), io::sink());
+
+        let err = vm.run().unwrap_err();
+        assert_eq!(
+            "error: cannot pop: stack is empty\n --> test.shrek:2:2\n  |\n2 | HH\n  |  ^\n  = instruction 2: Pop 0\n  = stack (top first): []",
+            err.render("test.shrek", "SRR\nHH\n")
+        );
+    }
+
+    #[test]
+    fn test_host_function() {
+        let byte_code = vec![push_const(7), push_const(5), push_const(64), func()];
+        let mut vm = ShrekVM::<i32>::with_io(byte_code, io::empty(), io::sink());
+
+        vm.register_function(64, 2, |vm| {
+            let v0 = vm.pop()?;
+            let v1 = vm.pop()?;
+            vm.push(v1 * 10 + v0);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(75, vm.run().unwrap());
+    }
+
+    #[test]
+    fn test_host_function_arity() {
+        let called = Cell::new(false);
+        let byte_code = vec![push_const(5), push_const(64), func()];
+        let mut vm = ShrekVM::<i32>::with_io(byte_code, io::empty(), io::sink());
+
+        vm.register_function(64, 2, |_| {
+            called.set(true);
+            Ok(())
+        })
+        .unwrap();
+
+        assert!(vm.run().is_err());
+        assert!(!called.get());
+    }
+
+    #[test]
+    fn test_host_function_not_registered() {
+        let byte_code = vec![push_const(65), func()];
+        let mut vm = ShrekVM::<i32>::with_io(byte_code, io::empty(), io::sink());
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn test_register_reserved_function() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+        assert!(vm
+            .register_function(builtins::BUILTIN_MAX, 0, |_| Ok(()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_register_duplicate_function() {
+        let mut vm = ShrekVM::<i32>::with_io(Vec::new(), io::empty(), io::sink());
+        vm.register_function(100, 0, |_| Ok(())).unwrap();
+        assert!(vm.register_function(100, 0, |_| Ok(())).is_err());
+    }
+
+    fn infinite_loop() -> Vec<ByteCode> {
+        // Push 1 forever by jumping back to the start.
+        vec![push_const(1), push_const(0), ByteCode::new(OpCode::Jump, 0)]
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let mut vm = ShrekVM::<i32>::with_io(infinite_loop(), io::empty(), io::sink());
+        vm.set_limits(Limits {
+            max_instructions: Some(100),
+            ..Limits::default()
+        });
+
+        let err = vm.run().unwrap_err();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::Instructions), err.kind);
+        assert_eq!(100, vm.instruction_count());
+    }
+
+    #[test]
+    fn test_stack_depth_limit() {
+        let mut vm = ShrekVM::<i32>::with_io(infinite_loop(), io::empty(), io::sink());
+        vm.set_limits(Limits {
+            max_stack_depth: Some(10),
+            ..Limits::default()
+        });
+
+        let err = vm.run().unwrap_err();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::StackDepth), err.kind);
+        assert_eq!(11, vm.count());
+    }
+
+    #[test]
+    fn test_output_limit() {
+        // Output 1 forever.
+        let byte_code = vec![
+            push_const(1),
+            push_const(builtins::ops::OUTPUT),
+            func(),
+            ByteCode::new(OpCode::Pop, 0),
+            push_const(0),
+            ByteCode::new(OpCode::Jump, 0),
+        ];
+
+        let mut output = Vec::new();
+        let mut vm = ShrekVM::<i32>::with_io(byte_code, io::empty(), &mut output);
+        vm.set_limits(Limits {
+            max_output_bytes: Some(5),
+            ..Limits::default()
+        });
+
+        let err = vm.run().unwrap_err();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::OutputBytes), err.kind);
+
+        drop(vm);
+        assert_eq!(b"1\n1\n1", &output[..]);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut vm = ShrekVM::<i32>::with_io(infinite_loop(), io::empty(), io::sink());
+        vm.set_limits(Limits {
+            deadline: Some(Instant::now() + std::time::Duration::from_millis(10)),
+            ..Limits::default()
+        });
+
+        let err = vm.run().unwrap_err();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::Deadline), err.kind);
+    }
+
+    fn call(address: i32) -> Vec<ByteCode> {
+        vec![push_const(3), ByteCode::new(OpCode::Jump, address)]
+    }
+
+    fn ret() -> Vec<ByteCode> {
+        vec![push_const(4), ByteCode::new(OpCode::Jump, -1)]
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        // Call the code at address 6 twice, then jump to the end. The called code pushes 1 and returns.
+        let mut byte_code = Vec::new();
+        byte_code.extend(call(6));
+        byte_code.extend(call(6));
+        byte_code.extend(vec![push_const(0), ByteCode::new(OpCode::Jump, 9)]);
+        byte_code.push(push_const(1));
+        byte_code.extend(ret());
+
+        let mut vm = ShrekVM::<i32>::with_io(byte_code, io::empty(), io::sink());
+        vm.resume().unwrap();
+        assert_eq!(vec![1, 1], vm.stack());
+        assert!(vm.return_stack().is_empty());
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        // Call the start of the program forever.
+        let mut vm = ShrekVM::<i32>::with_io(call(0), io::empty(), io::sink());
+        vm.set_limits(Limits {
+            max_call_depth: Some(10),
+            ..Limits::default()
+        });
+
+        let err = vm.run().unwrap_err();
+        assert_eq!(ErrorKind::LimitExceeded(Limit::CallDepth), err.kind);
+        assert_eq!(10, vm.return_stack().len());
+
+        vm.reset();
+        assert!(vm.return_stack().is_empty());
+    }
+
+    fn run_error(byte_code: Vec<ByteCode>) -> ErrorKind {
+        let mut vm = ShrekVM::<i32>::with_io(byte_code, io::empty(), io::sink());
+        vm.run().unwrap_err().kind
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        assert_eq!(
+            ErrorKind::StackUnderflow { needed: 1, had: 0 },
+            run_error(vec![ByteCode::new(OpCode::Pop, 0)])
+        );
+        assert_eq!(
+            ErrorKind::StackUnderflow { needed: 2, had: 1 },
+            run_error(vec![push_const(1), push_const(builtins::ops::ADD), func()])
+        );
+        assert_eq!(
+            ErrorKind::InvalidJumpType(7),
+            run_error(vec![push_const(7), ByteCode::new(OpCode::Jump, 0)])
+        );
+        assert_eq!(
+            ErrorKind::UnknownFunction(builtins::BUILTIN_MAX),
+            run_error(vec![push_const(builtins::BUILTIN_MAX), func()])
+        );
+        assert_eq!(
+            ErrorKind::UnknownFunction(100),
+            run_error(vec![push_const(100), func()])
+        );
+        assert_eq!(ErrorKind::ReturnWithoutCall, run_error(ret()));
+        assert_eq!(
+            ErrorKind::LabelNotFound,
+            run_error(vec![push_const(0), ByteCode::new(OpCode::Jump, -1)])
+        );
+        assert_eq!(
+            ErrorKind::DivisionByZero,
+            run_error(vec![
+                push_const(1),
+                push_const(0),
+                push_const(builtins::ops::DIVIDE),
+                func()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_error_is_std_error() {
+        let err: Box<dyn Error> = Box::new(ShrekRuntimeError::from(ErrorKind::LabelNotFound));
+        assert_eq!("Runtime Error: jump label not found", err.to_string());
+    }
+}
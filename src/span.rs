@@ -0,0 +1,154 @@
+use std::fmt;
+
+/// A region of source code. `line` and `column` are 1-based and refer to the start of the span. The column counts
+/// characters, not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Span {
+        Span {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    /// Create a span starting at this span and ending at the end of `other`.
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..*self
+        }
+    }
+
+    /// Render a message pointing at this span, in the style of rustc. `level` is the kind of message, such as
+    /// "error" or "note":
+    ///
+    /// ```text
+    /// error: invalid token
+    ///  --> script.shrek:3:4
+    ///   |
+    /// 3 | SRK!X!
+    ///   |    ^^^
+    /// ```
+    ///
+    /// With no source text, such as for a compiled file, only the location is rendered.
+    pub fn render(&self, level: &str, message: &str, file_name: &str, source: &str) -> String {
+        let gutter = " ".repeat(self.line.to_string().len());
+        if source.is_empty() {
+            return format!(
+                "{}: {}\n{}--> {}:{}",
+                level, message, gutter, file_name, self
+            );
+        }
+
+        format!(
+            "{}: {}\n{}--> {}:{}\n{}",
+            level,
+            message,
+            gutter,
+            file_name,
+            self,
+            self.snippet(source)
+        )
+    }
+
+    /// Render the source line this span starts on with the span underlined, in the style of rustc:
+    ///
+    /// ```text
+    ///   |
+    /// 3 | SRK!X!
+    ///   |    ^^^
+    /// ```
+    ///
+    /// Spans covering more than one line are underlined to the end of the first line.
+    pub fn snippet(&self, source: &str) -> String {
+        let line_start = source[..self.start.min(source.len())]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        let line_end = source[line_start..]
+            .find('\n')
+            .map_or(source.len(), |i| line_start + i);
+        let line_text = source[line_start..line_end].trim_end_matches('\r');
+
+        let underline_end = self.end.min(line_end).max(self.start);
+        let width = source
+            .get(self.start.min(line_end)..underline_end)
+            .map_or(0, |s| s.chars().count())
+            .max(1);
+
+        let line_num = self.line.to_string();
+        let gutter = " ".repeat(line_num.len());
+        format!(
+            "{gutter} |\n{line_num} | {line_text}\n{gutter} | {pad}{carets}",
+            gutter = gutter,
+            line_num = line_num,
+            line_text = line_text,
+            pad = " ".repeat(self.column.saturating_sub(1)),
+            carets = "^".repeat(width)
+        )
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let source = "SRR\nSRK!X!\n";
+        let span = Span::new(7, 10, 2, 4);
+
+        assert_eq!(
+            "error: invalid token\n --> test.shrek:2:4\n  |\n2 | SRK!X!\n  |    ^^^",
+            span.render("error", "invalid token", "test.shrek", source)
+        );
+    }
+
+    #[test]
+    fn test_render_without_source() {
+        let span = Span::new(7, 10, 2, 4);
+
+        assert_eq!(
+            "error: invalid token\n --> test.shrekc:2:4",
+            span.render("error", "invalid token", "test.shrekc", "")
+        );
+    }
+
+    #[test]
+    fn test_snippet() {
+        let source = "SRR\nSRK!X!\n";
+        let span = Span::new(7, 10, 2, 4);
+
+        assert_eq!("  |\n2 | SRK!X!\n  |    ^^^", span.snippet(source));
+    }
+
+    #[test]
+    fn test_snippet_empty_span() {
+        let source = "SRR";
+        let span = Span::new(3, 3, 1, 4);
+
+        assert_eq!("  |\n1 | SRR\n  |    ^", span.snippet(source));
+    }
+
+    #[test]
+    fn test_snippet_multi_line_span() {
+        let source = "# comment\nS";
+        let span = Span::new(0, 10, 1, 1);
+
+        assert_eq!("  |\n1 | # comment\n  | ^^^^^^^^^", span.snippet(source));
+    }
+}
@@ -0,0 +1,188 @@
+//! Differential tests for the optimizer. Each program is run both optimized and unoptimized, and the two runs must
+//! produce the same exit code, output and error.
+
+use shrek_lang_rust::arithmetic::ArithmeticMode;
+use shrek_lang_rust::cell::{CellType, CellValue};
+use shrek_lang_rust::shrek_parser::{generate_byte_code, SyntaxTree, Tokenizer};
+use shrek_lang_rust::shrek_vm::ShrekVM;
+use shrek_lang_rust::{compile_with, CompileOptions, Program};
+
+use num_bigint::BigInt;
+
+const MODES: [ArithmeticMode; 3] = [
+    ArithmeticMode::Checked,
+    ArithmeticMode::Wrapping,
+    ArithmeticMode::Saturating,
+];
+
+const CELL_TYPES: [CellType; 3] = [CellType::I32, CellType::I64, CellType::BigInt];
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Exit(String, Vec<u8>),
+    Error(String, Vec<u8>),
+}
+
+fn unoptimized(source: &str) -> Program {
+    let tokens = Tokenizer::new().tokenize(source).unwrap();
+    let syntax_tree = SyntaxTree::generate(&tokens).unwrap();
+    Program::from_byte_code(generate_byte_code(&syntax_tree).unwrap())
+}
+
+fn run<C: CellValue>(program: &Program, mode: ArithmeticMode, input: &str) -> Outcome {
+    let mut output = Vec::new();
+    let mut vm = ShrekVM::<C>::with_io(program.byte_code().to_vec(), input.as_bytes(), &mut output);
+    vm.set_arithmetic_mode(mode);
+    let result = vm.run();
+    drop(vm);
+
+    match result {
+        Ok(exit_code) => Outcome::Exit(exit_code.to_string(), output),
+        Err(err) => Outcome::Error(err.message, output),
+    }
+}
+
+fn run_cells(program: &Program, cell_type: CellType, mode: ArithmeticMode, input: &str) -> Outcome {
+    match cell_type {
+        CellType::I32 => run::<i32>(program, mode, input),
+        CellType::I64 => run::<i64>(program, mode, input),
+        CellType::BigInt => run::<BigInt>(program, mode, input),
+    }
+}
+
+fn assert_same_behavior(source: &str, input: &str) {
+    let unoptimized = unoptimized(source);
+
+    for cell_type in CELL_TYPES.iter() {
+        for mode in MODES.iter() {
+            let options = CompileOptions {
+                arithmetic_mode: *mode,
+                cell_type: *cell_type,
+                ..CompileOptions::default()
+            };
+            let optimized = compile_with(source, &options).unwrap();
+
+            let expected = run_cells(&unoptimized, *cell_type, *mode, input);
+            let actual = run_cells(&optimized, *cell_type, *mode, input);
+            assert_eq!(
+                expected, actual,
+                "optimized program behaves differently with {:?} cells in {:?} mode:\n{}",
+                cell_type, mode, source
+            );
+        }
+    }
+}
+
+#[test]
+fn test_corpus() {
+    let corpus = [
+        // Examples from the README.
+        "SRRR !R! S SRE R SRE R SRE H SR SRRRE SRK!E! SK!R! !E! S",
+        "SE !S! SRE H SRK!H! SK!S! !H!",
+        // Constants at the end of the program.
+        "SRRRR",
+        "SRR SRRR SRRE",
+        "SRRR SRRRRRRRRE",
+        // Constants split by a label must not be folded together.
+        "SRR SRRR !S! SRRE",
+        "SR !S! R R SRE H SRRK!S!",
+        "SRRR SRRRR SRRRRE SRK!E! SRRRRRRRE !E!",
+        // Code after an unconditional jump that can only be reached through a label.
+        "SK!E! SRRR SRE !E! SRR",
+        "SRR SRK!H! SRRRRRRRRE !H! SRRRE",
+        // Loops.
+        "SRRRRR !R! SRE SR SRRRE SRK!E! SK!R! !E!",
+        "SRRRRR !R! SRRRRRRRRRRE SRRRRRRRRE SRE H SR SRRRE SRK!E! SK!R! !E!",
+        // Calls. Constants must not be folded across the place a call returns to.
+        "SRRRRR SRRRK!S! SRRRRRRR SRRRK!S! H H S SK!E! !S! SRE SRRRRK!S! !E!",
+        "SRRR SRRRK!S! SRRRE SK!E! !S! SRR SRRRRK!S! !E!",
+        "SRRR !R! SR SRRRE SRK!E! SRRRK!R! !E! SRRRRK!E!",
+        // Globals.
+        "SRRRRR SRR SRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRE SRR SRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRE SRE",
+        "SRRR S SRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRE !R! S SRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRE SRE SR SRRRE SRK!E! S SRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRE SK!R! !E!",
+        "SRRRRR SRR SRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRE SE SRR SRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRE SRR SRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRE",
+        // Errors.
+        "H",
+        "SRRRRK!E! !E!",
+        "SR SRRRRRE",
+        "SRRRRRRRRRRRRRRRRRRRRE",
+        "SRRRR S SRRRRRE",
+        "SRRRR S SRRRRRRE",
+        "SRRRR SRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRR SRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRE",
+        // Overflow.
+        "SRRRRRRRRRRRRRRRR SRRRRRRRRRE SRRRRRRRRRE SRRRRRRRRRE SRE",
+        "SRRRRRRRRRRRRRRRR SRRRRRRRRRE SRRRRRRRRRE SRRRRRRRE SRRRRRRRRRE SRRRRRRRRE SRE",
+    ];
+
+    for source in corpus.iter() {
+        assert_same_behavior(source, "asdf\n");
+    }
+}
+
+/// Small linear congruential generator so the random programs are the same on every run.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) as usize) % bound
+    }
+}
+
+const LABEL_LETTERS: [char; 5] = ['S', 'H', 'R', 'E', 'K'];
+
+fn label_name(num: usize) -> String {
+    format!("!{}{}!", LABEL_LETTERS[num / 5], LABEL_LETTERS[num % 5])
+}
+
+fn push(value: usize) -> String {
+    format!("S{}", "R".repeat(value))
+}
+
+/// Generate a random program. Jumps only go forward, so every program terminates.
+fn random_program(rng: &mut Lcg) -> String {
+    // Every builtin except the ones that read from the input stream.
+    const FUNCS: [usize; 49] = [
+        1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28,
+        29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51,
+        52,
+    ];
+
+    let label_count = 1 + rng.next(4);
+    let mut parts = Vec::new();
+
+    for label in 0..label_count {
+        for _ in 0..rng.next(6) {
+            let part = match rng.next(6) {
+                0 => push(rng.next(5)),
+                1 => String::from("R"),
+                2 => String::from("H"),
+                3 | 4 => format!(
+                    "{} {}E",
+                    push(rng.next(4)),
+                    push(FUNCS[rng.next(FUNCS.len())])
+                ),
+                _ => {
+                    let target = label + rng.next(label_count - label);
+                    format!("{}K{}", push(rng.next(3)), label_name(target))
+                }
+            };
+            parts.push(part);
+        }
+        parts.push(label_name(label));
+    }
+
+    parts.join(" ")
+}
+
+#[test]
+fn test_random_programs() {
+    let mut rng = Lcg(0x5348_5245_4b21);
+    for _ in 0..500 {
+        let source = random_program(&mut rng);
+        assert_same_behavior(&source, "");
+    }
+}
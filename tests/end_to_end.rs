@@ -0,0 +1,90 @@
+//! Runs whole programs, from source to exit code, with input and output kept in memory.
+
+use shrek_lang_rust::arithmetic::ArithmeticMode;
+use shrek_lang_rust::compile;
+use shrek_lang_rust::limits::{Limit, Limits};
+use shrek_lang_rust::shrek_vm::{ErrorKind, ShrekVM, VmResult};
+
+use std::io;
+
+/// Compile and run `source` with `input`, returning the result of the run and the output.
+fn run(source: &str, input: &str) -> (VmResult<i32>, String) {
+    let program = compile(source).unwrap();
+
+    let mut output = Vec::new();
+    let result = program.run_with(input.as_bytes(), &mut output);
+
+    (result, String::from_utf8(output).unwrap())
+}
+
+#[test]
+fn test_demo_example() {
+    let (result, output) = run(include_str!("../examples/demo.shrek"), "");
+    assert_eq!(0, result.unwrap());
+    assert_eq!("0\n1\n2\n0\n1\n2\n0\n1\n2\n", output);
+}
+
+#[test]
+fn test_globals_example() {
+    let (result, output) = run(include_str!("../examples/globals.shrek"), "");
+    assert_eq!(0, result.unwrap());
+    assert_eq!("3\n2\n1\n", output);
+}
+
+#[test]
+fn test_io_example() {
+    let (result, output) = run(include_str!("../examples/io.shrek"), "h\u{e9}llo\n");
+    assert_eq!(0, result.unwrap());
+    assert_eq!("h\u{e9}llo\n", output);
+}
+
+#[test]
+fn test_call_and_return() {
+    let source = "
+        SRRRRR SRRRK!S! # Push 5 and call !S!
+        SRRRRRRR SRRRK!S! # Push 7 and call !S!
+        SRRE SK!E! # Add and exit
+        !S! SRE SRRRRK!S! # Print {1} and return
+        !E!";
+
+    let (result, output) = run(source, "");
+    assert_eq!(12, result.unwrap());
+    assert_eq!("5\n7\n", output);
+}
+
+#[test]
+fn test_runtime_error() {
+    let (result, output) = run("SRRRR SRE SRRRE", "");
+    assert_eq!(
+        ErrorKind::StackUnderflow { needed: 2, had: 1 },
+        result.unwrap_err().kind
+    );
+    assert_eq!("4\n", output);
+}
+
+#[test]
+fn test_builder_settings() {
+    // Double 1 until it overflows.
+    let program = compile(&format!("SR {}", "SRRRRRRRE ".repeat(31))).unwrap();
+    let mut vm = ShrekVM::<i32>::builder(program.byte_code().to_vec())
+        .input(io::empty())
+        .output(io::sink())
+        .arithmetic_mode(ArithmeticMode::Wrapping)
+        .build();
+    assert_eq!(i32::MIN, vm.run().unwrap());
+
+    let limits = Limits {
+        max_instructions: Some(10),
+        ..Limits::default()
+    };
+    let program = compile("!R! SK!R!").unwrap();
+    let mut vm = ShrekVM::<i32>::builder(program.byte_code().to_vec())
+        .input(io::empty())
+        .output(io::sink())
+        .limits(limits)
+        .build();
+    assert_eq!(
+        ErrorKind::LimitExceeded(Limit::Instructions),
+        vm.run().unwrap_err().kind
+    );
+}
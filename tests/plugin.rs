@@ -0,0 +1,76 @@
+use shrek_lang_rust::compile;
+use shrek_lang_rust::plugin::load_plugin;
+use shrek_lang_rust::shrek_vm::ShrekVM;
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Build the example plugin from the workspace and return the path to the shared library.
+fn example_plugin_path() -> PathBuf {
+    // Build with the same profile as the tests, so the plugin lands next to them.
+    let mut command = Command::new(env!("CARGO"));
+    command.args(["build", "-p", "shrek_example_plugin"]);
+    if !cfg!(debug_assertions) {
+        command.arg("--release");
+    }
+    let status = command
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "failed to build example plugin");
+
+    // Test executables live in target/<profile>/deps, the plugin is placed in target/<profile>.
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.push(format!(
+        "{}shrek_example_plugin{}",
+        env::consts::DLL_PREFIX,
+        env::consts::DLL_SUFFIX
+    ));
+    path
+}
+
+/// Source code that pushes `value` to the stack.
+fn push(value: usize) -> String {
+    format!("S{} ", "R".repeat(value))
+}
+
+/// Source code that calls function `func_num`.
+fn call(func_num: usize) -> String {
+    format!("{}E ", push(func_num))
+}
+
+fn run_with_plugin(source: &str) -> Result<i32, String> {
+    let program = compile(source).unwrap();
+    let mut vm = ShrekVM::with_io(program.byte_code().to_vec(), io::empty(), io::sink());
+
+    let count = unsafe { load_plugin(&mut vm, example_plugin_path()) }.unwrap();
+    assert_eq!(3, count);
+
+    vm.run().map_err(|e| e.message)
+}
+
+#[test]
+fn test_plugin_function() {
+    // max(2, 3)
+    let source = push(2) + &push(3) + &call(64);
+    assert_eq!(3, run_with_plugin(&source).unwrap());
+}
+
+#[test]
+fn test_plugin_stack_callbacks() {
+    // depth pushes the number of items on the stack.
+    let source = push(7) + &push(8) + &call(65);
+    assert_eq!(2, run_with_plugin(&source).unwrap());
+}
+
+#[test]
+fn test_plugin_failure() {
+    let err = run_with_plugin(&call(66)).unwrap_err();
+    assert!(err.contains("code 42"));
+}